@@ -14,12 +14,20 @@ mod download {
 	include!("src/download.rs");
 }
 
+mod edition {
+	include!("src/edition.rs");
+}
+
+mod history {
+	include!("src/history.rs");
+}
+
 mod utils {
 	include!("src/utils.rs");
 }
 
 
-use config::{Config, MajorWinVer};
+use config::{Config, HttpConfig, MajorWinVer};
 use esd_downloader::{FileInfo, WinEsdDownloader};
 
 fn get_property(file: &FileInfo, prop: &str, version: &str) -> Option<String> {
@@ -51,6 +59,15 @@ fn build_property_enums(version_files: &HashMap<MajorWinVer, Vec<FileInfo>>) ->
 			}
 		}
 
+		// SKU names like Pro N, their EDITIONIDs and aliases besides the catalog editions
+		if prop == "edition" {
+			for sku in edition::SKUS {
+				vals.push(sku.name.to_string());
+				vals.push(sku.edition_id.to_string());
+				vals.extend(sku.aliases.iter().map(|alias| alias.to_string()));
+			}
+		}
+
 		vals.sort();
 		vals.dedup();
 
@@ -64,10 +81,22 @@ fn build_property_enums(version_files: &HashMap<MajorWinVer, Vec<FileInfo>>) ->
 
 fn main() -> anyhow::Result<()> {
 	let mut schema = schema_for!(Config);
-	let downloader = WinEsdDownloader::new("./.rinbcache/esd_cache")?;
-
-	let files10: Vec<FileInfo> = downloader.files(&MajorWinVer::Win10)?;
-	let files11: Vec<FileInfo> = downloader.files(&MajorWinVer::Win11)?;
+	let mut downloader = WinEsdDownloader::new("./.rinbcache/esd_cache", &HttpConfig::default())?;
+
+	// air-gapped builds only use cached catalogs
+	downloader.offline = env::var_os("RINB_OFFLINE").is_some();
+
+	let files = downloader
+		.files(&MajorWinVer::Win10)
+		.and_then(|files10| Ok((files10, downloader.files(&MajorWinVer::Win11)?)));
+	let (files10, files11): (Vec<FileInfo>, Vec<FileInfo>) = match files {
+		Ok(files) => files,
+		Err(err) if downloader.offline => {
+			println!("cargo:warning=Keeping existing schema, {err}");
+			return Ok(());
+		}
+		Err(err) => return Err(err),
+	};
 
 	let mut version_files = HashMap::new();
 	version_files.insert(MajorWinVer::Win10, files10);
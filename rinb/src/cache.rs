@@ -0,0 +1,312 @@
+use std::{
+	collections::HashSet,
+	fs::{self, File},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use anyhow::{Error, Result, anyhow};
+
+use crate::download::{fdownload, verified_path, write_verified};
+use crate::esd_downloader::{CacheIndex, migrate_cache};
+use crate::lock::read_locks;
+use crate::utils::set_accessed;
+
+pub struct CacheEntry {
+	pub path: PathBuf,
+	pub sha1: String,
+	pub size: u64,
+	pub last_used: SystemTime,
+	/// human readable names from the index, e.g. {file}-{lang}-{edition}-{arch}
+	pub names: Vec<String>,
+}
+
+impl CacheEntry {
+	pub fn name(&self) -> String {
+		self.path
+			.file_name()
+			.unwrap_or_default()
+			.to_string_lossy()
+			.to_string()
+	}
+}
+
+/// ESD files in a cache directory, named {sha1}.esd
+pub struct EsdCache {
+	pub directory: PathBuf,
+}
+
+impl EsdCache {
+	pub fn new(directory: impl AsRef<Path>) -> Self {
+		Self {
+			directory: directory.as_ref().to_path_buf(),
+		}
+	}
+
+	/// renames cache files of older versions, see migrate_cache
+	pub fn migrate(&self) -> Result<(), Error> {
+		migrate_cache(&self.directory)
+	}
+
+	/// all entries, least recently used first
+	pub fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+		let mut entries = Vec::new();
+		if !self.directory.exists() {
+			return Ok(entries);
+		}
+		let mut index = CacheIndex::load(&self.directory)?;
+
+		for dir_entry in fs::read_dir(&self.directory)? {
+			let path = dir_entry?.path();
+			if !path.is_file() || path.extension().is_none_or(|ext| ext != "esd") {
+				continue;
+			}
+
+			let sha1 = path
+				.file_stem()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.to_string();
+			if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+				continue;
+			}
+
+			let metadata = fs::metadata(&path)?;
+			let last_used = metadata.accessed().or_else(|_| metadata.modified())?;
+			entries.push(CacheEntry {
+				names: index
+					.names
+					.remove(&sha1)
+					.unwrap_or_default()
+					.into_iter()
+					.collect(),
+				sha1,
+				size: metadata.len(),
+				last_used,
+				path,
+			});
+		}
+
+		entries.sort_by_key(|e| e.last_used);
+		Ok(entries)
+	}
+
+	/// re-hashes the entry and compares it against the sha1 in its name
+	pub fn verify(&self, entry: &CacheEntry) -> Result<(), Error> {
+		let res = fdownload(
+			File::open(&entry.path)?,
+			None,
+			&0,
+			&entry.size,
+			Some(&entry.sha1),
+			None,
+			&format!("Verifying (hashing) {:?}\n", entry.name()),
+		);
+
+		let digests = res?;
+		// reading isn't a use
+		if let Err(err) = set_accessed(&entry.path, entry.last_used) {
+			eprintln!(
+				"Failed to restore the access time of {:?}: {err}",
+				entry.name()
+			);
+		}
+		write_verified(&entry.path, &digests)?;
+		Ok(())
+	}
+
+	pub fn remove(&self, entry: &CacheEntry) -> Result<(), Error> {
+		fs::remove_file(&entry.path)?;
+		let sidecar = verified_path(&entry.path);
+		if sidecar.exists() {
+			fs::remove_file(sidecar)?;
+		}
+		CacheIndex::remove(&self.directory, &entry.sha1)?;
+		Ok(())
+	}
+}
+
+/// sha1s referenced by lock files, by any of their profiles
+pub fn referenced_sha1s(lock_paths: &[PathBuf]) -> Result<HashSet<String>, Error> {
+	let mut sha1s = HashSet::new();
+	for lock_path in lock_paths {
+		for (_, lock) in read_locks(lock_path)? {
+			let (sha1, _) = lock.config.parse_sha1size()?;
+			sha1s.insert(sha1);
+		}
+	}
+	Ok(sha1s)
+}
+
+pub struct PruneOptions {
+	/// remove entries not used for this long
+	pub older_than: Option<Duration>,
+	/// keep only this many most recently used entries
+	pub keep: Option<usize>,
+	/// remove least recently used entries until the total size fits
+	pub max_size: Option<u64>,
+	/// remove entries not referenced by these sha1s
+	pub referenced: Option<HashSet<String>>,
+}
+
+/// entries which should be removed according to options, least recently used first
+pub fn prune_candidates(entries: Vec<CacheEntry>, options: &PruneOptions) -> Vec<CacheEntry> {
+	let now = SystemTime::now();
+	let mut total_size: u64 = entries.iter().map(|e| e.size).sum();
+	let count = entries.len();
+
+	let mut remove = Vec::new();
+	for (index, entry) in entries.into_iter().enumerate() {
+		let too_old = options.older_than.is_some_and(|older_than| {
+			now.duration_since(entry.last_used).unwrap_or_default() > older_than
+		});
+		let beyond_keep = options.keep.is_some_and(|keep| count - index > keep);
+		let too_large = options
+			.max_size
+			.is_some_and(|max_size| total_size > max_size);
+		let unreferenced = options
+			.referenced
+			.as_ref()
+			.is_some_and(|referenced| !referenced.contains(&entry.sha1));
+
+		if too_old || beyond_keep || too_large || unreferenced {
+			total_size -= entry.size;
+			remove.push(entry);
+		}
+	}
+	remove
+}
+
+/// parses sizes like 4096, 500M or 20GiB (binary units)
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+	let s = s.trim();
+	let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+	let (number, unit) = s.split_at(digits_end);
+	let number = number
+		.parse::<u64>()
+		.map_err(|_| anyhow!("Invalid size {s}"))?;
+
+	let shift = match unit
+		.trim()
+		.to_ascii_lowercase()
+		.trim_end_matches("ib")
+		.trim_end_matches('b')
+	{
+		"" => 0,
+		"k" => 10,
+		"m" => 20,
+		"g" => 30,
+		"t" => 40,
+		_ => {
+			return Err(anyhow!(
+				"Invalid size unit in {s}, expected one of K, M, G, T"
+			));
+		}
+	};
+	Ok(number << shift)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(sha1: &str, size: u64, days_ago: u64) -> CacheEntry {
+		CacheEntry {
+			path: PathBuf::from(format!("{sha1}.esd")),
+			sha1: sha1.to_string(),
+			size,
+			last_used: SystemTime::now() - Duration::from_secs(days_ago * 24 * 60 * 60),
+			names: Vec::new(),
+		}
+	}
+
+	/// least recently used first, like EsdCache::entries
+	fn entries() -> Vec<CacheEntry> {
+		vec![entry("a", 300, 30), entry("b", 200, 10), entry("c", 100, 1)]
+	}
+
+	fn pruned(options: PruneOptions) -> Vec<String> {
+		prune_candidates(entries(), &options)
+			.into_iter()
+			.map(|e| e.sha1)
+			.collect()
+	}
+
+	fn options() -> PruneOptions {
+		PruneOptions {
+			older_than: None,
+			keep: None,
+			max_size: None,
+			referenced: None,
+		}
+	}
+
+	#[test]
+	fn prune_nothing_without_options() {
+		assert!(pruned(options()).is_empty());
+	}
+
+	#[test]
+	fn prune_by_age_keep_and_size() {
+		let older_than = Some(Duration::from_secs(7 * 24 * 60 * 60));
+		assert_eq!(
+			pruned(PruneOptions {
+				older_than,
+				..options()
+			}),
+			["a", "b"]
+		);
+		assert_eq!(
+			pruned(PruneOptions {
+				keep: Some(1),
+				..options()
+			}),
+			["a", "b"]
+		);
+		assert!(
+			pruned(PruneOptions {
+				keep: Some(5),
+				..options()
+			})
+			.is_empty()
+		);
+		assert_eq!(
+			pruned(PruneOptions {
+				max_size: Some(300),
+				..options()
+			}),
+			["a"]
+		);
+		assert_eq!(
+			pruned(PruneOptions {
+				max_size: Some(0),
+				..options()
+			}),
+			["a", "b", "c"]
+		);
+	}
+
+	#[test]
+	fn prune_unreferenced() {
+		let referenced = Some(HashSet::from(["b".to_string()]));
+		assert_eq!(
+			pruned(PruneOptions {
+				referenced,
+				..options()
+			}),
+			["a", "c"]
+		);
+	}
+
+	#[test]
+	fn sizes() {
+		assert_eq!(parse_size("4096").unwrap(), 4096);
+		assert_eq!(parse_size("500M").unwrap(), 500 << 20);
+		assert_eq!(parse_size("20GiB").unwrap(), 20 << 30);
+		assert_eq!(parse_size(" 1 kb ").unwrap(), 1 << 10);
+		assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+		assert!(parse_size("").is_err());
+		assert!(parse_size("G").is_err());
+		assert!(parse_size("10X").is_err());
+	}
+}
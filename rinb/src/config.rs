@@ -1,6 +1,9 @@
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
 fn default_lang() -> String {
 	"en-us".to_string()
@@ -22,6 +25,30 @@ impl Arch {
 			Arch::X86 => "x86",
 		}
 	}
+
+	/// name in the config
+	pub fn name(&self) -> &'static str {
+		match self {
+			Arch::Amd64 => "amd64",
+			Arch::Arm64 => "arm64",
+			Arch::X86 => "x86",
+		}
+	}
+}
+
+impl FromStr for Arch {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"amd64" | "x64" => Ok(Arch::Amd64),
+			"arm64" => Ok(Arch::Arm64),
+			"x86" => Ok(Arch::X86),
+			_ => Err(anyhow!(
+				"Unknown architecture {s}, expected one of amd64, arm64, x86"
+			)),
+		}
+	}
 }
 
 fn default_arch() -> Arch {
@@ -46,6 +73,20 @@ impl MajorWinVer {
 	}
 }
 
+impl FromStr for MajorWinVer {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"10" => Ok(MajorWinVer::Win10),
+			"11" => Ok(MajorWinVer::Win11),
+			_ => Err(anyhow!(
+				"Unknown windows version {s}, expected one of 10, 11"
+			)),
+		}
+	}
+}
+
 fn default_major_winver() -> MajorWinVer {
 	MajorWinVer::Win11
 }
@@ -54,29 +95,90 @@ fn default_edition() -> String {
 	"Professional".to_string()
 }
 
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct HttpConfig {
+	#[schemars(description = "Proxy URL used for all requests, e.g. http://proxy:3128")]
+	pub proxy: Option<String>,
+	#[schemars(description = "Path to a PEM file with additional root certificates")]
+	pub ca_bundle: Option<String>,
+	#[schemars(description = "Connect timeout in seconds")]
+	pub connect_timeout: Option<u64>,
+	#[schemars(description = "Read timeout in seconds, defaults to 30")]
+	pub read_timeout: Option<u64>,
+	#[schemars(description = "Retries for failed requests, defaults to 3")]
+	pub retries: Option<u32>,
+	#[schemars(
+		description = "Seconds to wait before the first retry, doubled for every further retry. Defaults to 2"
+	)]
+	pub retry_backoff: Option<u64>,
+}
+
 #[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
 pub struct Config {
 	#[schemars(default = "default_lang", description = "Target language")]
 	pub lang: String,
 	#[schemars(default = "default_arch", description = "Target architecture")]
 	pub arch: Arch,
-	#[schemars(default = "default_edition", description = "Windows edition")]
+	#[schemars(
+		default = "default_edition",
+		description = "Windows edition, a SKU like Home, Pro N or Education, or an edition of the catalog like Core. Selects the ESD from the catalog and the install image by its EDITIONID."
+	)]
 	pub edition: String,
 	#[schemars(
 		default = "default_major_winver",
 		description = "Major windows version"
 	)]
 	pub version: MajorWinVer,
+	#[schemars(
+		regex(pattern = r"^[0-9]+\.[0-9]+$"),
+		description = "Windows build to pin to, e.g. 26100.4349. Looked up in the catalog and its history, the resolved file is written to the lock file."
+	)]
+	pub build: Option<String>,
 	#[schemars(
 		regex(pattern = r"^[0-9a-f]{40}:[0-9]+$"),
 		description = "{sha1}:{sizeInBytes} for pinning"
 	)]
 	pub sha1size: Option<String>,
 	#[schemars(
-		url,
-		description = "Optional URL for pinning. Requires sha1size to be defined."
+		regex(pattern = r"^sha256:[0-9a-f]{64}:[0-9]+$"),
+		description = "sha256:{sha256}:{sizeInBytes} for pinning with SHA256 in addition to sha1size. Written to the lock file after the first download."
+	)]
+	pub pin: Option<String>,
+	#[schemars(
+		description = "Optional URL, file:// URL or local path for pinning. Requires sha1size to be defined."
 	)]
 	pub url: Option<String>,
+	#[serde(default)]
+	#[schemars(
+		description = "Mirror URLs (or local paths), tried in order before url. {sha1} is replaced with the sha1 of the file, e.g. http://host:8080/{sha1}.esd for a `rinb cache serve` mirror. Each one is verified against sha1size."
+	)]
+	pub mirrors: Vec<String>,
+	#[schemars(
+		description = "URL which actually served the pinned file. Written to the lock file."
+	)]
+	pub served_by: Option<String>,
+	#[schemars(
+		description = "Local path or file:// URL of a windows ISO to use instead of downloading an ESD. Verified against sha1size if defined."
+	)]
+	pub iso: Option<String>,
+	#[serde(default)]
+	#[schemars(description = "HTTP client options for catalog and ESD downloads")]
+	pub http: HttpConfig,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	#[schemars(
+		description = "Path of a config this one is merged over, relative to this file. Objects are merged field by field, any other value (including arrays and null) replaces that of the extended config. The lock file stores the merged config."
+	)]
+	pub extends: Option<String>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	#[schemars(
+		description = "Variants of this config by name, e.g. {\"pro-arm\": {\"arch\": \"arm64\"}}. Each one is merged into this config and may set `out`. Built with --profile {name} or `rinb build --all`, locked separately."
+	)]
+	pub profiles: BTreeMap<String, Value>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	#[schemars(
+		description = "Values per field, e.g. {\"arch\": [\"amd64\", \"arm64\"]}. Adds a profile for every combination, named by its values joined with -. Entries of profiles with the same name are merged into them."
+	)]
+	pub matrix: BTreeMap<String, Vec<Value>>,
 }
 
 impl Config {
@@ -94,4 +196,71 @@ impl Config {
 		};
 		Err(Error::msg("sha1size not provided"))
 	}
+
+	/// sha256 and size of pin, if provided
+	pub fn parse_pin(&self) -> Result<Option<(String, u64)>, Error> {
+		let Some(pin) = &self.pin else {
+			return Ok(None);
+		};
+		let format_err =
+			|| anyhow!("pin must be in format 'sha256:{{sha256}}:{{sizeInBytes}}', got {pin}");
+		let (algorithm, rest) = pin.split_once(':').ok_or_else(format_err)?;
+		let (hash, size_str) = rest.split_once(':').ok_or_else(format_err)?;
+		if algorithm != "sha256" {
+			return Err(anyhow!(
+				"Unsupported hash algorithm {algorithm} in pin, expected sha256"
+			));
+		}
+		if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+			return Err(format_err());
+		}
+		let size = size_str
+			.parse::<u64>()
+			.map_err(|_| Error::msg("size must be a valid u64"))?;
+		Ok(Some((hash.to_ascii_lowercase(), size)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+	fn pinned(pin: &str) -> Config {
+		serde_json::from_value(json!({
+			"version": "11",
+			"arch": "amd64",
+			"edition": "Professional",
+			"lang": "en-us",
+			"pin": pin,
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn parse_pin() {
+		let pin = pinned(&format!("sha256:{}:4096", SHA256.to_uppercase()));
+		assert_eq!(pin.parse_pin().unwrap(), Some((SHA256.to_string(), 4096)));
+		let mut unpinned = pin;
+		unpinned.pin = None;
+		assert_eq!(unpinned.parse_pin().unwrap(), None);
+	}
+
+	#[test]
+	fn malformed_pin() {
+		for pin in [
+			"",
+			SHA256,
+			&format!("sha256:{SHA256}"),
+			&format!("md5:{SHA256}:4096"),
+			"sha256:abc:4096",
+			&format!("sha256:{}:4096", "g".repeat(64)),
+			&format!("sha256:{SHA256}:big"),
+			&format!("sha256:{SHA256}:-1"),
+		] {
+			assert!(pinned(pin).parse_pin().is_err(), "{pin}");
+		}
+	}
 }
@@ -1,27 +1,83 @@
-use std::fs::File;
-use std::io::{BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+	Mutex,
+	atomic::{AtomicBool, Ordering},
+};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
 use anyhow::{Error, Result, anyhow};
 use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::{
+	Certificate, Proxy, StatusCode, Url,
+	blocking::Client,
+	header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
-pub fn fdownload<R: Read>(
-	mut reader: R,
-	cache_file_path: Option<&PathBuf>,
-	expected_size: &u64,
-	expected_sha1: &str,
+use crate::config::HttpConfig;
+use crate::utils::ExpectEqual;
+
+pub fn http_client(http: &HttpConfig) -> Result<Client, Error> {
+	let mut builder = Client::builder();
+	if let Some(proxy) = &http.proxy {
+		builder = builder.proxy(Proxy::all(proxy)?);
+	}
+	if let Some(ca_bundle) = &http.ca_bundle {
+		let pem = fs::read(ca_bundle)
+			.map_err(|err| anyhow!("Failed to read CA bundle {ca_bundle}: {err}"))?;
+		for cert in Certificate::from_pem_bundle(&pem)? {
+			builder = builder.add_root_certificate(cert);
+		}
+	}
+	if let Some(connect_timeout) = http.connect_timeout {
+		builder = builder.connect_timeout(Duration::from_secs(connect_timeout));
+	}
+	if let Some(read_timeout) = http.read_timeout {
+		// applies to every read for blocking clients
+		builder = builder.timeout(Duration::from_secs(read_timeout));
+	}
+	Ok(builder.build()?)
+}
+
+/// network errors, but not client errors like 404
+fn is_transient(err: &Error) -> bool {
+	if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+		return !err.status().is_some_and(|status| status.is_client_error());
+	}
+	err.downcast_ref::<io::Error>().is_some()
+}
+
+/// runs f, retrying transient errors with exponential backoff
+pub fn with_retries<T>(
+	http: &HttpConfig,
 	label: &str,
-) -> Result<(), Error> {
-	let cache_file: Option<File>;
-	if let Some(filepath) = cache_file_path {
-		cache_file = Some(File::create(filepath)?);
-	} else {
-		cache_file = Option::None;
+	mut f: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+	let retries = http.retries.unwrap_or(3);
+	let mut backoff = Duration::from_secs(http.retry_backoff.unwrap_or(2));
+
+	let mut attempt = 0;
+	loop {
+		let res = f();
+		match res {
+			Err(err) if attempt < retries && is_transient(&err) => {
+				attempt += 1;
+				eprintln!("{label} failed, retrying in {backoff:?} ({attempt}/{retries}):\n{err}");
+				thread::sleep(backoff);
+				backoff *= 2;
+			}
+			res => return res,
+		}
 	}
+}
 
-	// Setup progress bar
-	let pb = ProgressBar::new(*expected_size);
+fn progress_bar(size: &u64, label: &str) -> ProgressBar {
+	let pb = ProgressBar::new(*size);
 	pb.set_style(
     ProgressStyle::default_bar()
         .template(
@@ -31,8 +87,48 @@ pub fn fdownload<R: Read>(
         .progress_chars("#>-"),
 );
 	pb.set_message(label.to_string());
+	pb
+}
+
+/// hashes of a file, computed in the same pass
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Digests {
+	pub sha1: String,
+	pub sha256: String,
+}
+
+/// Hashes everything `reader` yields and writes it to `cache_file_path`.
+/// The first `resume_from` bytes are expected to already exist in the file,
+/// they are hashed but not written again.
+/// Returns the SHA1 and SHA256, which are verified against `expected_sha1`
+/// and `expected_sha256` if given, after checking that `expected_size` bytes were read.
+pub fn fdownload<R: Read>(
+	mut reader: R,
+	cache_file_path: Option<&PathBuf>,
+	resume_from: &u64,
+	expected_size: &u64,
+	expected_sha1: Option<&str>,
+	expected_sha256: Option<&str>,
+	label: &str,
+) -> Result<Digests, Error> {
+	let cache_file: Option<File>;
+	if let Some(filepath) = cache_file_path {
+		if *resume_from > 0 {
+			let mut file = OpenOptions::new().write(true).open(filepath)?;
+			file.set_len(*resume_from)?;
+			file.seek(SeekFrom::End(0))?;
+			cache_file = Some(file);
+		} else {
+			cache_file = Some(File::create(filepath)?);
+		}
+	} else {
+		cache_file = Option::None;
+	}
+
+	let pb = progress_bar(expected_size, label);
 
 	let mut hasher = Sha1::new();
+	let mut sha256_hasher = Sha256::new();
 	let mut buffer = vec![0; 256 * 1024]; // 1MB buffer
 	let mut writer = cache_file.as_ref().map(|f| BufWriter::new(f));
 
@@ -47,9 +143,14 @@ pub fn fdownload<R: Read>(
 		}
 
 		if let Some(writer) = writer.as_mut() {
-			writer.write_all(&buffer[..bytes_read])?;
+			// skip the part which is already on disk
+			let skip = resume_from
+				.saturating_sub(total_bytes)
+				.min(bytes_read as u64) as usize;
+			writer.write_all(&buffer[skip..bytes_read])?;
 		}
 		hasher.update(&buffer[..bytes_read]);
+		sha256_hasher.update(&buffer[..bytes_read]);
 
 		total_bytes += bytes_read as u64;
 		update_counter += bytes_read;
@@ -70,38 +171,729 @@ pub fn fdownload<R: Read>(
 	let finish_message = format!("Finished {}", label);
 	pb.finish_with_message(finish_message);
 
+	// a connection closed early ends the reader just like a complete download,
+	// the partial file is kept and resumed on retry
+	if total_bytes < *expected_size {
+		return Err(io::Error::new(
+			io::ErrorKind::UnexpectedEof,
+			format!("Short read: expected {expected_size} bytes, got {total_bytes}"),
+		)
+		.into());
+	}
+	total_bytes.expect_equal(*expected_size, "Size mismatch")?;
+
 	// Verify SHA1
 	let actual_sha1 = hex::encode(hasher.finalize());
-	if actual_sha1 != expected_sha1 {
+	if let Some(expected_sha1) = expected_sha1
+		&& actual_sha1 != expected_sha1
+	{
 		return Err(anyhow!(
 			"SHA1 mismatch: expected {}, got {}",
 			expected_sha1,
 			actual_sha1
 		));
 	}
+	let actual_sha256 = hex::encode(sha256_hasher.finalize());
+	if let Some(expected_sha256) = expected_sha256
+		&& actual_sha256 != expected_sha256
+	{
+		return Err(anyhow!(
+			"SHA256 mismatch: expected {expected_sha256}, got {actual_sha256}"
+		));
+	}
+
+	Ok(Digests {
+		sha1: actual_sha1,
+		sha256: actual_sha256,
+	})
+}
 
+/// metadata of a file at the time it was verified
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct Verified {
+	size: u64,
+	/// seconds and nanoseconds since the unix epoch
+	mtime: (u64, u32),
+	inode: Option<u64>,
+	sha1: String,
+	/// missing in sidecars of older versions
+	sha256: Option<String>,
+}
+
+impl Verified {
+	fn of(path: &PathBuf, digests: &Digests) -> Result<Self, Error> {
+		let metadata = fs::metadata(path)?;
+		let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+
+		#[cfg(unix)]
+		let inode = Some(std::os::unix::fs::MetadataExt::ino(&metadata));
+		#[cfg(not(unix))]
+		let inode = None;
+
+		Ok(Self {
+			size: metadata.len(),
+			mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+			inode,
+			sha1: digests.sha1.clone(),
+			sha256: Some(digests.sha256.clone()),
+		})
+	}
+}
+
+/// `{path}.verified`, the sidecar which allows skipping re-hashing `path`
+pub fn verified_path(path: &Path) -> PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_os_string();
+	name.push(".verified");
+	path.with_file_name(name)
+}
+
+/// records that `path` has been verified to match `digests`
+pub fn write_verified(path: &PathBuf, digests: &Digests) -> Result<(), Error> {
+	let verified = Verified::of(path, digests)?;
+	fs::write(
+		verified_path(path),
+		serde_json::to_string_pretty(&verified)?,
+	)?;
 	Ok(())
 }
 
-pub fn download_from_url(
-	url: &String,
+/// digests `path` has been verified to match, if it has been verified to match `sha1`
+/// and not been modified since
+pub fn verified_digests(path: &PathBuf, sha1: &str) -> Option<Digests> {
+	recorded_digests(path).filter(|digests| digests.sha1 == sha1)
+}
+
+/// digests `path` has been verified to match, if it has not been modified since
+pub fn recorded_digests(path: &PathBuf) -> Option<Digests> {
+	let recorded: Verified = fs::read_to_string(verified_path(path))
+		.ok()
+		.and_then(|data| serde_json::from_str(&data).ok())?;
+	let digests = Digests {
+		sha1: recorded.sha1.clone(),
+		sha256: recorded.sha256.clone()?,
+	};
+	Verified::of(path, &digests)
+		.is_ok_and(|current| current == recorded)
+		.then_some(digests)
+}
+
+/// `{cache_file_path}.part`, where interrupted downloads are kept for resuming
+pub fn part_path(cache_file_path: &Path) -> PathBuf {
+	let mut name = cache_file_path
+		.file_name()
+		.unwrap_or_default()
+		.to_os_string();
+	name.push(".part");
+	cache_file_path.with_file_name(name)
+}
+
+/// local path of `url`, if it is a file:// URL or a plain path
+pub fn local_path(url: &str) -> Option<PathBuf> {
+	if url.starts_with("file:") {
+		return Url::parse(url).ok()?.to_file_path().ok();
+	}
+	if url.contains("://") {
+		return None;
+	}
+	Some(PathBuf::from(url))
+}
+
+/// Hardlinks (or copies, if that is not possible) the local file `path` to
+/// `cache_file_path` and verifies it.
+pub fn copy_from_path(
+	path: &PathBuf,
 	cache_file_path: &PathBuf,
 	expected_size: &u64,
 	expected_sha1: &str,
+) -> Result<Digests, Error> {
+	fs::metadata(path)?
+		.len()
+		.expect_equal(*expected_size, format!("Size mismatch for {path:?}"))?;
+
+	let part_file_path = &part_path(cache_file_path);
+	if part_file_path.exists() {
+		fs::remove_file(part_file_path)?;
+	}
+
+	let res = if fs::hard_link(path, part_file_path).is_ok() {
+		fdownload(
+			File::open(part_file_path)?,
+			None,
+			&0,
+			expected_size,
+			Some(expected_sha1),
+			None,
+			&format!("Verifying (hashing) hardlinked {path:?}\n"),
+		)
+	} else {
+		fdownload(
+			File::open(path)?,
+			Some(part_file_path),
+			&0,
+			expected_size,
+			Some(expected_sha1),
+			None,
+			&format!("Copying and verifying (hashing) {path:?}\n"),
+		)
+	};
+	let digests = match res {
+		Err(err) => {
+			fs::remove_file(part_file_path)?;
+			return Err(err);
+		}
+		Ok(digests) => digests,
+	};
+
+	fs::rename(part_file_path, cache_file_path)?;
+	Ok(digests)
+}
+
+/// Downloads `start..=end` of `url` into `file_path` at the same offset
+fn download_range(
+	client: &Client,
+	url: &String,
+	file_path: &PathBuf,
+	start: u64,
+	end: u64,
+	pb: &ProgressBar,
+) -> Result<(), Error> {
+	let response = client
+		.get(url)
+		.header(RANGE, format!("bytes={start}-{end}"))
+		.send()?
+		.error_for_status()?;
+	if response.status() != StatusCode::PARTIAL_CONTENT {
+		return Err(anyhow!("Server ignored range request for {url}"));
+	}
+
+	let mut file = OpenOptions::new().write(true).open(file_path)?;
+	file.seek(SeekFrom::Start(start))?;
+	let mut writer = BufWriter::new(file);
+
+	let mut reader = response.take(end - start + 1);
+	let mut buffer = vec![0; 256 * 1024];
+	let mut total_bytes = 0;
+	let res = (|| -> Result<(), Error> {
+		loop {
+			let bytes_read = reader.read(&mut buffer)?;
+			if bytes_read == 0 {
+				break;
+			}
+			writer.write_all(&buffer[..bytes_read])?;
+			total_bytes += bytes_read as u64;
+			pb.inc(bytes_read as u64);
+		}
+		writer.flush()?;
+		total_bytes.expect_equal(
+			end - start + 1,
+			format!("Incomplete range {start}-{end} of {url}"),
+		)?;
+		Ok(())
+	})();
+	if res.is_err() {
+		// the whole range is downloaded again
+		pb.dec(total_bytes);
+	}
+	res
+}
+
+/// largest range requested at once, finished ranges are recorded for resuming
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// `{part_file_path}.ranges`, the finished ranges of a chunked download
+fn ranges_path(part_file_path: &Path) -> PathBuf {
+	let mut name = part_file_path
+		.file_name()
+		.unwrap_or_default()
+		.to_os_string();
+	name.push(".ranges");
+	part_file_path.with_file_name(name)
+}
+
+/// finished `start..=end` ranges of the chunked download into `part_file_path`,
+/// None if it isn't one
+fn read_ranges(part_file_path: &Path) -> Option<Vec<(u64, u64)>> {
+	let data = fs::read_to_string(ranges_path(part_file_path)).ok()?;
+	serde_json::from_str(&data).ok()
+}
+
+fn write_ranges(part_file_path: &Path, ranges: &[(u64, u64)]) -> Result<(), Error> {
+	fs::write(ranges_path(part_file_path), serde_json::to_string(ranges)?)?;
+	Ok(())
+}
+
+/// ranges of `0..size` not covered by `done`, split into chunks of at most `chunk_size`
+fn missing_ranges(done: &[(u64, u64)], size: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+	let mut done = done.to_vec();
+	done.sort();
+	let mut gaps = Vec::new();
+	let mut next = 0;
+	for (start, end) in done {
+		if start > next {
+			gaps.push((next, start.min(size)));
+		}
+		next = next.max(end + 1);
+	}
+	if next < size {
+		gaps.push((next, size));
+	}
+
+	let mut missing = Vec::new();
+	for (mut start, end) in gaps {
+		while start < end {
+			let chunk_end = (start + chunk_size).min(end);
+			missing.push((start, chunk_end - 1));
+			start = chunk_end;
+		}
+	}
+	missing
+}
+
+/// end of the contiguous bytes from the start of the file covered by `done`
+fn contiguous_len(done: &[(u64, u64)]) -> u64 {
+	let mut done = done.to_vec();
+	done.sort();
+	let mut len = 0;
+	for (start, end) in done {
+		if start > len {
+			break;
+		}
+		len = len.max(end + 1);
+	}
+	len
+}
+
+/// Downloads the ranges of `url` missing from `done` over `connections` parallel
+/// range requests into the preallocated `part_file_path`.
+/// Every finished range is recorded in `{part_file_path}.ranges`, so that an
+/// interrupted download resumes with the missing ones.
+/// Failed ranges are retried on their own, once one fails for good the others stop.
+fn download_chunked(
+	client: &Client,
+	http: &HttpConfig,
+	url: &String,
+	part_file_path: &PathBuf,
+	done: Vec<(u64, u64)>,
+	expected_size: &u64,
+	connections: usize,
 ) -> Result<(), Error> {
-	let response = reqwest::blocking::get(url)?;
-	let reader = response.error_for_status()?;
+	{
+		let file = OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(part_file_path)?;
+		file.set_len(*expected_size)?;
+	}
+	write_ranges(part_file_path, &done)?;
 
-	fdownload(
-		reader.take(*expected_size),
-		Some(cache_file_path),
+	let pb = progress_bar(
 		expected_size,
-		expected_sha1,
 		&format!(
-			"Downloading and verifying (hashing) {:?}\n",
-			&cache_file_path.file_name().unwrap()
+			"Downloading {:?} over {connections} connections\n",
+			&part_file_path.file_name().unwrap()
 		),
-	)?;
+	);
+	let finished: u64 = done.iter().map(|(start, end)| end - start + 1).sum();
+	pb.set_position(finished);
+
+	let remaining = expected_size - finished;
+	let chunk_size = remaining
+		.div_ceil(connections as u64)
+		.clamp(1, MAX_CHUNK_SIZE);
+	let mut missing = missing_ranges(&done, *expected_size, chunk_size);
+	// popped from the end
+	missing.reverse();
+	let queue = Mutex::new(missing);
+	let done = Mutex::new(done);
+	let failed = AtomicBool::new(false);
+
+	thread::scope(|s| -> Result<(), Error> {
+		let handles: Vec<_> = (0..connections)
+			.map(|_| {
+				let (pb, queue, done, failed) = (&pb, &queue, &done, &failed);
+				s.spawn(move || -> Result<(), Error> {
+					loop {
+						if failed.load(Ordering::Relaxed) {
+							return Ok(());
+						}
+						let Some((start, end)) = queue.lock().unwrap().pop() else {
+							return Ok(());
+						};
+						let label = format!("Downloading range {start}-{end} of {url}");
+						let res = with_retries(http, &label, || {
+							download_range(client, url, part_file_path, start, end, pb)
+						});
+						if let Err(err) = res {
+							failed.store(true, Ordering::Relaxed);
+							return Err(err);
+						}
+						let mut done = done.lock().unwrap();
+						done.push((start, end));
+						write_ranges(part_file_path, &done)?;
+					}
+				})
+			})
+			.collect();
+
+		for handle in handles {
+			handle
+				.join()
+				.map_err(|_| anyhow!("Download thread panicked"))??;
+		}
+		Ok(())
+	})?;
 
+	pb.finish_with_message(format!(
+		"Finished downloading {:?}",
+		&part_file_path.file_name().unwrap()
+	));
 	Ok(())
 }
+
+/// whether `url` can be downloaded in ranges, per a HEAD request
+fn accepts_ranges(client: &Client, url: &str, expected_size: u64) -> bool {
+	let head = match client.head(url).send().and_then(|r| r.error_for_status()) {
+		Ok(head) => head,
+		Err(err) => {
+			eprintln!("HEAD {url} failed: {err}");
+			return false;
+		}
+	};
+	let headers = head.headers();
+	headers.get(ACCEPT_RANGES).is_some_and(|v| v == "bytes")
+		&& headers
+			.get(CONTENT_LENGTH)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.parse::<u64>().ok())
+			== Some(expected_size)
+}
+
+/// Downloads `url` to `cache_file_path` over `{cache_file_path}.part`.
+/// An existing partial file is resumed with `Range: bytes={len}-` if the server
+/// accepts ranges, and hashed together with the new bytes.
+/// With `connections > 1`, the remaining bytes are split into ranges which are
+/// downloaded in parallel, and the assembled file is hashed afterwards.
+/// If the server doesn't accept ranges, it falls back to a single connection.
+/// Transient errors are retried: single ranges on their own, a single connection
+/// download by resuming it.
+pub fn download_from_url(
+	client: &Client,
+	http: &HttpConfig,
+	url: &String,
+	cache_file_path: &PathBuf,
+	expected_size: &u64,
+	expected_sha1: &str,
+	connections: usize,
+) -> Result<Digests, Error> {
+	let part_file_path = &part_path(cache_file_path);
+
+	let chunked = read_ranges(part_file_path)
+		.filter(|_| fs::metadata(part_file_path).is_ok_and(|m| m.len() == *expected_size));
+
+	if connections > 1 && accepts_ranges(client, url, *expected_size) {
+		// a partial single connection download is the range before its end
+		let done = chunked.unwrap_or_else(|| match resume_len(part_file_path, expected_size) {
+			0 => Vec::new(),
+			len => vec![(0, len - 1)],
+		});
+		download_chunked(
+			client,
+			http,
+			url,
+			part_file_path,
+			done,
+			expected_size,
+			connections,
+		)?;
+		let _ = fs::remove_file(ranges_path(part_file_path));
+
+		let res = fdownload(
+			File::open(part_file_path)?,
+			None,
+			&0,
+			expected_size,
+			Some(expected_sha1),
+			None,
+			&format!(
+				"Verifying (hashing) {:?}\n",
+				&cache_file_path.file_name().unwrap()
+			),
+		);
+		let digests = match res {
+			Err(err) => {
+				fs::remove_file(part_file_path)?;
+				return Err(err);
+			}
+			Ok(digests) => digests,
+		};
+
+		fs::rename(part_file_path, cache_file_path)?;
+		return Ok(digests);
+	}
+	if connections > 1 {
+		eprintln!("Server does not accept ranges, downloading {url} over a single connection");
+	}
+	if let Some(done) = chunked {
+		// continue after the bytes the chunked download finished from the start
+		OpenOptions::new()
+			.write(true)
+			.open(part_file_path)?
+			.set_len(contiguous_len(&done))?;
+		fs::remove_file(ranges_path(part_file_path))?;
+	}
+
+	with_retries(http, &format!("Downloading from {url}"), || {
+		download_single(client, url, cache_file_path, expected_size, expected_sha1)
+	})
+}
+
+/// length of the partial single connection download at `part_file_path` to resume from,
+/// removing it if it can't be resumed
+fn resume_len(part_file_path: &Path, expected_size: &u64) -> u64 {
+	match fs::metadata(part_file_path) {
+		Ok(metadata) if metadata.len() < *expected_size => metadata.len(),
+		Ok(_) => {
+			let _ = fs::remove_file(part_file_path);
+			0
+		}
+		Err(_) => 0,
+	}
+}
+
+/// downloads `url` over a single connection, resuming `{cache_file_path}.part`
+fn download_single(
+	client: &Client,
+	url: &String,
+	cache_file_path: &PathBuf,
+	expected_size: &u64,
+	expected_sha1: &str,
+) -> Result<Digests, Error> {
+	let part_file_path = &part_path(cache_file_path);
+	let mut resume_from = resume_len(part_file_path, expected_size);
+
+	let mut request = client.get(url);
+	if resume_from > 0 {
+		request = request.header(RANGE, format!("bytes={resume_from}-"));
+	}
+	let response = request.send()?.error_for_status()?;
+
+	if resume_from > 0 {
+		// servers without Accept-Ranges ignore the Range header and send everything
+		let content_range = response
+			.headers()
+			.get(CONTENT_RANGE)
+			.and_then(|v| v.to_str().ok())
+			.unwrap_or_default();
+		if response.status() != StatusCode::PARTIAL_CONTENT
+			|| !content_range.starts_with(&format!("bytes {resume_from}-"))
+		{
+			eprintln!("Server does not support resuming, downloading {url} again");
+			resume_from = 0;
+		}
+	}
+
+	let label = if resume_from > 0 {
+		format!(
+			"Resuming download at {resume_from} bytes and verifying (hashing) {:?}\n",
+			&cache_file_path.file_name().unwrap()
+		)
+	} else {
+		format!(
+			"Downloading and verifying (hashing) {:?}\n",
+			&cache_file_path.file_name().unwrap()
+		)
+	};
+
+	let prefix: Box<dyn Read> = if resume_from > 0 {
+		Box::new(File::open(part_file_path)?.take(resume_from))
+	} else {
+		Box::new(std::io::empty())
+	};
+
+	let res = fdownload(
+		prefix.chain(response.take(*expected_size - resume_from)),
+		Some(part_file_path),
+		&resume_from,
+		expected_size,
+		Some(expected_sha1),
+		None,
+		&label,
+	);
+	let digests = match res {
+		Err(err) => {
+			// complete but corrupted files can't be resumed
+			if fs::metadata(part_file_path).is_ok_and(|m| m.len() >= *expected_size) {
+				fs::remove_file(part_file_path)?;
+			}
+			return Err(err);
+		}
+		Ok(digests) => digests,
+	};
+
+	fs::rename(part_file_path, cache_file_path)?;
+	Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::TmpDir;
+
+	fn digests(data: &[u8]) -> Digests {
+		fdownload(data, None, &0, &(data.len() as u64), None, None, "").unwrap()
+	}
+
+	#[test]
+	fn resume_from_part_file() {
+		let tmp = TmpDir::new().unwrap();
+		let part_file_path = &tmp.path.join("test.esd.part");
+		let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+		let expected = digests(&data);
+		// a stale tail beyond the resume point is overwritten
+		fs::write(part_file_path, [&data[..40_000], &[0; 100]].concat()).unwrap();
+
+		let resume_from = 40_000;
+		let prefix = File::open(part_file_path).unwrap().take(resume_from);
+		let res = fdownload(
+			prefix.chain(&data[resume_from as usize..]),
+			Some(part_file_path),
+			&resume_from,
+			&(data.len() as u64),
+			Some(&expected.sha1),
+			Some(&expected.sha256),
+			"",
+		);
+		assert_eq!(res.unwrap(), expected);
+		assert_eq!(fs::read(part_file_path).unwrap(), data);
+	}
+
+	fn set_modified(path: &Path, time: std::time::SystemTime) {
+		let file = File::options().write(true).open(path).unwrap();
+		file.set_modified(time).unwrap();
+	}
+
+	#[test]
+	fn modified_files_are_not_verified() {
+		let tmp = TmpDir::new().unwrap();
+		let path = &tmp.path.join("test.esd");
+		fs::write(path, b"content").unwrap();
+		let expected = digests(b"content");
+		write_verified(path, &expected).unwrap();
+		assert_eq!(
+			verified_digests(path, &expected.sha1),
+			Some(expected.clone())
+		);
+		assert_eq!(verified_digests(path, &"0".repeat(40)), None);
+		let mtime = fs::metadata(path).unwrap().modified().unwrap();
+
+		// touched
+		set_modified(path, mtime + Duration::from_secs(1));
+		assert_eq!(verified_digests(path, &expected.sha1), None);
+		set_modified(path, mtime);
+		assert_eq!(
+			verified_digests(path, &expected.sha1),
+			Some(expected.clone())
+		);
+
+		// resized
+		fs::write(path, b"content!").unwrap();
+		set_modified(path, mtime);
+		assert_eq!(verified_digests(path, &expected.sha1), None);
+
+		// replaced by another file of the same size and mtime
+		let other = &tmp.path.join("other.esd");
+		fs::write(other, b"CONTENT").unwrap();
+		set_modified(other, mtime);
+		fs::rename(other, path).unwrap();
+		assert_eq!(verified_digests(path, &expected.sha1).is_none(), cfg!(unix));
+	}
+
+	#[test]
+	fn local_paths() {
+		#[cfg(unix)]
+		assert_eq!(
+			local_path("file:///srv/esd/x.esd"),
+			Some(PathBuf::from("/srv/esd/x.esd"))
+		);
+		#[cfg(windows)]
+		{
+			assert_eq!(
+				local_path("file:///C:/esd/x.esd"),
+				Some(PathBuf::from(r"C:\esd\x.esd"))
+			);
+			assert_eq!(
+				local_path("file://server/share/x.esd"),
+				Some(PathBuf::from(r"\\server\share\x.esd"))
+			);
+		}
+		assert_eq!(
+			local_path("/srv/esd/x.esd"),
+			Some(PathBuf::from("/srv/esd/x.esd"))
+		);
+		assert_eq!(local_path("x.esd"), Some(PathBuf::from("x.esd")));
+		assert_eq!(
+			local_path(r"\\server\share\x.esd"),
+			Some(PathBuf::from(r"\\server\share\x.esd"))
+		);
+		assert_eq!(local_path("https://host/x.esd"), None);
+		assert_eq!(local_path("http://host:8080/{sha1}.esd"), None);
+	}
+
+	#[test]
+	fn copy_from_local_path() {
+		let tmp = TmpDir::new().unwrap();
+		let (source, cache_file_path) = (&tmp.path.join("source.esd"), &tmp.path.join("x.esd"));
+		fs::write(source, b"content").unwrap();
+		let expected = digests(b"content");
+
+		let err = copy_from_path(source, cache_file_path, &7, &"0".repeat(40)).unwrap_err();
+		assert!(err.to_string().starts_with("SHA1 mismatch"), "{err}");
+		assert!(!cache_file_path.exists() && !part_path(cache_file_path).exists());
+		assert!(copy_from_path(source, cache_file_path, &8, &expected.sha1).is_err());
+
+		let res = copy_from_path(source, cache_file_path, &7, &expected.sha1);
+		assert_eq!(res.unwrap(), expected);
+		assert_eq!(fs::read(cache_file_path).unwrap(), b"content");
+		assert!(source.exists() && !part_path(cache_file_path).exists());
+	}
+
+	#[test]
+	fn short_read_is_not_a_digest_mismatch() {
+		let data = [7u8; 1000];
+		let expected = digests(&data);
+		let err = fdownload(
+			&data[..600],
+			None,
+			&0,
+			&1000,
+			Some(&expected.sha1),
+			None,
+			"",
+		)
+		.unwrap_err();
+		assert!(err.to_string().starts_with("Short read"), "{err}");
+		assert!(is_transient(&err));
+
+		let err = fdownload(&data[..], None, &0, &600, None, None, "").unwrap_err();
+		assert!(err.to_string().starts_with("Size mismatch"), "{err}");
+	}
+
+	#[test]
+	fn missing_ranges_skip_finished() {
+		assert_eq!(missing_ranges(&[], 10, 4), vec![(0, 3), (4, 7), (8, 9)]);
+		assert_eq!(
+			missing_ranges(&[(6, 7), (0, 2)], 10, 4),
+			vec![(3, 5), (8, 9)]
+		);
+		assert_eq!(missing_ranges(&[(0, 9)], 10, 4), vec![]);
+	}
+
+	#[test]
+	fn contiguous_len_stops_at_gap() {
+		assert_eq!(contiguous_len(&[]), 0);
+		assert_eq!(contiguous_len(&[(4, 9), (0, 3)]), 10);
+		assert_eq!(contiguous_len(&[(0, 2), (5, 9)]), 3);
+		assert_eq!(contiguous_len(&[(1, 9)]), 0);
+	}
+}
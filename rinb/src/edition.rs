@@ -0,0 +1,178 @@
+/// windows edition as chosen in the config.
+///
+/// The catalog lists the ESDs by catalog edition, each ESD bundles several images
+/// identified by their WINDOWS/EDITIONID, which isn't always the catalog edition
+pub struct Sku {
+	/// name in the config, e.g. Pro N
+	pub name: &'static str,
+	/// WINDOWS/EDITIONID of the install image
+	pub edition_id: &'static str,
+	/// catalog editions whose ESD includes the image, most specific first
+	pub catalog: &'static [&'static str],
+	/// other names besides name and edition_id
+	pub aliases: &'static [&'static str],
+}
+
+pub const SKUS: &[Sku] = &[
+	Sku {
+		name: "Home",
+		edition_id: "Core",
+		catalog: &["Core", "Professional"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Home N",
+		edition_id: "CoreN",
+		catalog: &["CoreN", "ProfessionalN"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Home Single Language",
+		edition_id: "CoreSingleLanguage",
+		catalog: &["CoreSingleLanguage", "Core", "Professional"],
+		aliases: &["Single Language"],
+	},
+	Sku {
+		name: "Pro",
+		edition_id: "Professional",
+		catalog: &["Professional"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Pro N",
+		edition_id: "ProfessionalN",
+		catalog: &["ProfessionalN"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Pro Education",
+		edition_id: "ProfessionalEducation",
+		catalog: &["ProfessionalEducation", "Professional"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Pro Education N",
+		edition_id: "ProfessionalEducationN",
+		catalog: &["ProfessionalEducationN", "ProfessionalN"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Pro for Workstations",
+		edition_id: "ProfessionalWorkstation",
+		catalog: &["ProfessionalWorkstation", "Professional"],
+		aliases: &["Pro Workstation"],
+	},
+	Sku {
+		name: "Pro N for Workstations",
+		edition_id: "ProfessionalWorkstationN",
+		catalog: &["ProfessionalWorkstationN", "ProfessionalN"],
+		aliases: &["Pro N Workstation"],
+	},
+	Sku {
+		name: "Education",
+		edition_id: "Education",
+		catalog: &["Education", "Professional"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Education N",
+		edition_id: "EducationN",
+		catalog: &["EducationN", "ProfessionalN"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Enterprise",
+		edition_id: "Enterprise",
+		catalog: &["Enterprise"],
+		aliases: &[],
+	},
+	Sku {
+		name: "Enterprise N",
+		edition_id: "EnterpriseN",
+		catalog: &["EnterpriseN"],
+		aliases: &[],
+	},
+];
+
+/// lowercase without spaces, dashes and underscores, so that Pro N matches ProN
+fn normalize(name: &str) -> String {
+	name.chars()
+		.filter(|c| !matches!(c, ' ' | '-' | '_'))
+		.flat_map(char::to_lowercase)
+		.collect()
+}
+
+/// SKU named edition by its name, EDITIONID or an alias
+pub fn sku(edition: &str) -> Option<&'static Sku> {
+	let edition = normalize(edition);
+	SKUS.iter().find(|sku| {
+		[sku.name, sku.edition_id]
+			.iter()
+			.chain(sku.aliases)
+			.any(|name| normalize(name) == edition)
+	})
+}
+
+/// WINDOWS/EDITIONID of the install image for edition, edition itself if it's no known SKU
+pub fn edition_id(edition: &str) -> &str {
+	sku(edition).map_or(edition, |sku| sku.edition_id)
+}
+
+/// catalog editions listing an ESD with edition, most specific first
+pub fn catalog_editions(edition: &str) -> Vec<&str> {
+	match sku(edition) {
+		Some(sku) => sku.catalog.to_vec(),
+		None => vec![edition],
+	}
+}
+
+/// names of the SKUs included in the ESDs of any of the catalog editions
+pub fn skus_in(catalog_editions: &[&str]) -> Vec<&'static str> {
+	SKUS.iter()
+		.filter(|sku| {
+			sku.catalog.iter().any(|edition| {
+				catalog_editions
+					.iter()
+					.any(|listed| listed.eq_ignore_ascii_case(edition))
+			})
+		})
+		.map(|sku| sku.name)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sku_by_name_edition_id_or_alias() {
+		for edition in ["Pro N", "ProN", "pro-n", "PRO_N", "ProfessionalN"] {
+			assert_eq!(sku(edition).map(|sku| sku.name), Some("Pro N"), "{edition}");
+		}
+		assert_eq!(
+			sku("single language").map(|sku| sku.edition_id),
+			Some("CoreSingleLanguage")
+		);
+		assert_eq!(
+			sku("Pro Workstation").map(|sku| sku.name),
+			Some("Pro for Workstations")
+		);
+		assert!(sku("Ultimate").is_none());
+	}
+
+	#[test]
+	fn unknown_editions_pass_through() {
+		assert_eq!(edition_id("Home"), "Core");
+		assert_eq!(edition_id("CloudEdition"), "CloudEdition");
+		assert_eq!(catalog_editions("Education"), ["Education", "Professional"]);
+		assert_eq!(catalog_editions("CloudEdition"), ["CloudEdition"]);
+	}
+
+	#[test]
+	fn skus_in_catalog_editions() {
+		let skus = skus_in(&["professionaln"]);
+		assert!(skus.contains(&"Pro N"));
+		assert!(skus.contains(&"Education N"));
+		assert!(!skus.contains(&"Pro"));
+	}
+}
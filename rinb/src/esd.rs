@@ -15,13 +15,24 @@ use wimlib::{
 	WriteFlags, string::TStr, tstr,
 };
 
+use crate::edition::edition_id;
+use crate::remote_cache::RemoteCache;
 use crate::utils::ExpectEqual;
+
+/// version of the build outputs in the remote cache, part of their key.
+///
+/// Bump it whenever boot.wim or install.esd are written differently,
+/// so that outputs of older versions aren't pulled
+const BUILD_OUTPUT_VERSION: u32 = 1;
+
 pub struct EsdFile<'a> {
 	pub path: &'a PathBuf,
 	pub wiml: WimLib,
 	pub info: WimInfo,
 	pub wim: Wim,
 	pub n_threads: u32,
+	/// remote cache for the outputs of install_dir and the sha1 of this ESD they're keyed by
+	pub remote_cache: Option<(&'a RemoteCache, String)>,
 }
 
 impl<'a> EsdFile<'a> {
@@ -38,6 +49,7 @@ impl<'a> EsdFile<'a> {
 			info: info,
 			wim: wim,
 			n_threads: n_threads,
+			remote_cache: None,
 		});
 	}
 
@@ -101,14 +113,44 @@ impl<'a> EsdFile<'a> {
 
 	// find install.esd based on edition
 	pub fn install(&self, edition: &str) -> Result<Option<Image<'_>>, Error> {
-		// 0 to image_count: add to install.esd for image which matches EDITIONID
+		self.image_by_edition(4, edition)
+	}
+
+	/// windows build of the install image for edition, e.g. 10.0.26100.4349
+	pub fn build(&self, edition: &str) -> Result<Option<String>, Error> {
+		let Some(install) = self.install(edition)? else {
+			return Ok(None);
+		};
+		let version: Option<Vec<String>> = [
+			tstr!("WINDOWS/VERSION/MAJOR"),
+			tstr!("WINDOWS/VERSION/MINOR"),
+			tstr!("WINDOWS/VERSION/BUILD"),
+			tstr!("WINDOWS/VERSION/SPBUILD"),
+		]
+		.into_iter()
+		.map(|name| {
+			install
+				.property(name)
+				.map(|value| value.to_str().to_string())
+		})
+		.collect();
+		Ok(version.map(|version| version.join(".")))
+	}
+
+	/// image from first_index to image_count whose EDITIONID matches the SKU edition
+	pub fn image_by_edition(
+		&self,
+		first_index: u32,
+		edition: &str,
+	) -> Result<Option<Image<'_>>, Error> {
+		let edition_id = edition_id(edition);
 		let mut install_esd: Option<Image> = None;
-		for index in 4..=self.info.image_count {
+		for index in first_index..=self.info.image_count {
 			let wim = self.wim.select_image(ImageIndex::new(index).unwrap());
 
 			// only add images where editionID matches
 			let edition_got = wim.property(tstr!("WINDOWS/EDITIONID")).unwrap();
-			if edition_got.to_str() == edition {
+			if edition_got.to_str() == edition_id {
 				if install_esd.is_some() {
 					return Err(anyhow!(
 						"Multiple install images matching selected edition ({edition}) found"
@@ -174,6 +216,30 @@ impl<'a> EsdFile<'a> {
 		Ok(())
 	}
 
+	/// like write, but pulls the result from the remote cache if it has it, and pushes it otherwise
+	fn write_cached(
+		&self,
+		wim: &Image,
+		path: &PathBuf,
+		max_file_size: &u64,
+		name: &str,
+	) -> Result<(), Error> {
+		let Some((remote_cache, sha1)) = &self.remote_cache else {
+			return self.write(wim, path, max_file_size);
+		};
+		let key = format!("build/v{BUILD_OUTPUT_VERSION}/{sha1}/{name}-{max_file_size}");
+		if remote_cache.pull(&key, path, None).is_some() {
+			return Ok(());
+		}
+
+		self.write(wim, path, max_file_size)?;
+		// split wims aren't cached
+		if path.exists() {
+			remote_cache.push(&key, path, None);
+		}
+		Ok(())
+	}
+
 	pub fn install_dir(
 		&self,
 		target_dir: &PathBuf,
@@ -189,14 +255,24 @@ impl<'a> EsdFile<'a> {
 		let boot_wim = self.boot()?;
 		// let boot_wim = self.win_pe()?; // write win_pe for testing instead
 
-		self.write(&boot_wim.select_all_images(), &boot_wim_path, max_file_size)?;
+		self.write_cached(
+			&boot_wim.select_all_images(),
+			&boot_wim_path,
+			max_file_size,
+			"boot.wim",
+		)?;
 
 		// write install.esd to dism
 		let install_esd = match self.install(edition)? {
 			Some(esd) => esd,
 			None => return Err(anyhow!("install.esd not found in image")),
 		};
-		self.write(&install_esd, &install_esd_path, max_file_size)?;
+		self.write_cached(
+			&install_esd,
+			&install_esd_path,
+			max_file_size,
+			&format!("install-{}.esd", edition_id(edition)),
+		)?;
 
 		// extract base image
 		let base_image = self.base()?;
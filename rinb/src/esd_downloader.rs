@@ -1,17 +1,26 @@
-use crate::config::{Config, MajorWinVer};
+use crate::config::{Arch, Config, HttpConfig, MajorWinVer};
+use crate::edition::{catalog_editions, skus_in};
 
-use crate::download::{download_from_url, fdownload};
-use crate::utils::ExpectEqual;
+use crate::history::CatalogHistory;
 
+use crate::download::{
+	Digests, copy_from_path, download_from_url, fdownload, http_client, local_path,
+	verified_digests, verified_path, with_retries, write_verified,
+};
+use crate::utils::{ExpectEqual, closest, mark_used};
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
 use std::string::String;
+use std::time::Duration;
 
-use anyhow::{Error, Ok, Result, anyhow};
+use anyhow::{Error, Result, anyhow};
 use roxmltree::Document;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
 	pub file_name: String,
 	pub language_code: String,
@@ -23,6 +32,117 @@ pub struct FileInfo {
 	pub file_path: String,
 }
 
+impl FileInfo {
+	/// whether this file matches all given filters (case insensitive).
+	///
+	/// edition matches the catalog editions whose ESD includes it, see edition::catalog_editions
+	pub fn matches(&self, lang: Option<&str>, edition: Option<&str>, arch: Option<&Arch>) -> bool {
+		lang.is_none_or(|lang| self.language_code.eq_ignore_ascii_case(lang))
+			&& edition.is_none_or(|edition| {
+				catalog_editions(edition)
+					.iter()
+					.any(|catalog_edition| self.edition.eq_ignore_ascii_case(catalog_edition))
+			}) && arch.is_none_or(|arch| self.architecture.eq_ignore_ascii_case(arch.as_str()))
+	}
+
+	/// build number from the file name, see parse_build
+	pub fn build(&self) -> String {
+		parse_build(&self.file_name)
+	}
+}
+
+/// error naming the fields of config no file matches, with the values the catalog lists
+/// for them given the other fields and the closest of those
+fn check_fields(config: &Config, files: &[FileInfo]) -> Result<(), Error> {
+	type Field = (
+		&'static str,
+		String,
+		fn(&FileInfo) -> String,
+		fn(&FileInfo, &Config) -> bool,
+	);
+	let fields: [Field; 3] = [
+		(
+			"lang",
+			config.lang.clone(),
+			|file| file.language_code.clone(),
+			|file, config| file.matches(Some(&config.lang), None, None),
+		),
+		(
+			"edition",
+			config.edition.clone(),
+			|file| file.edition.clone(),
+			|file, config| file.matches(None, Some(&config.edition), None),
+		),
+		(
+			"arch",
+			config.arch.name().to_string(),
+			|file| {
+				file.architecture
+					.parse::<Arch>()
+					.map(|arch| arch.name().to_string())
+					.unwrap_or_else(|_| file.architecture.clone())
+			},
+			|file, config| file.matches(None, None, Some(&config.arch)),
+		),
+	];
+	let matches = |file: &FileInfo, index: usize| fields[index].3(file, config);
+
+	let mut problems = Vec::new();
+	for (index, (name, value, get, _)) in fields.iter().enumerate() {
+		// files matching the other fields, all files if one of those is wrong as well
+		let others: Vec<&FileInfo> = files
+			.iter()
+			.filter(|file| (0..fields.len()).all(|other| other == index || matches(file, other)))
+			.collect();
+		let (candidates, scope) = if others.is_empty() {
+			(files.iter().collect::<Vec<_>>(), String::new())
+		} else {
+			let chosen: Vec<String> = fields
+				.iter()
+				.enumerate()
+				.filter(|(other, _)| *other != index)
+				.map(|(_, (name, value, _, _))| format!("{name} {value}"))
+				.collect();
+			(others, format!(" with {}", chosen.join(", ")))
+		};
+		if candidates.iter().any(|file| matches(file, index)) {
+			continue;
+		}
+
+		let mut valid: BTreeSet<String> = candidates.iter().map(|file| get(file)).collect();
+		if *name == "edition" {
+			let catalog: Vec<&str> = valid.iter().map(String::as_str).collect();
+			let skus: Vec<String> = skus_in(&catalog).into_iter().map(String::from).collect();
+			valid.extend(skus);
+		}
+		let valid: Vec<&str> = valid.iter().map(String::as_str).collect();
+		let hint = match closest(value, &valid) {
+			Some(closest) => format!(". Did you mean {closest}?"),
+			None => String::new(),
+		};
+		problems.push(format!(
+			"{name} {value} isn't in the catalog of windows {}{scope}, it lists {}{hint}",
+			config.version.as_str(),
+			valid.join(", ")
+		));
+	}
+	if problems.is_empty() {
+		return Ok(());
+	}
+	Err(anyhow!(problems.join("\n")))
+}
+
+/// build number from an ESD file name,
+/// e.g. 26100.4349 for 26100.4349.250607-1500.ge_release_svc_refresh_CLIENTCONSUMER_RET_x64FRE_en-us.esd
+pub fn parse_build(file_name: &str) -> String {
+	file_name
+		.split('.')
+		.take_while(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+		.take(2)
+		.collect::<Vec<_>>()
+		.join(".")
+}
+
 // Traversal function
 
 fn find_files(xml: &str) -> Result<Vec<FileInfo>, Error> {
@@ -62,8 +182,8 @@ pub fn filename_without_extension(url: &String) -> Result<String, Error> {
 	let end = url.find(|c| c == '?' || c == '#').unwrap_or(url.len());
 	let path = &url[..end];
 
-	// Get the last segment after '/'
-	let filename = match path.rfind('/') {
+	// Get the last segment after '/' (or '\\' for local paths)
+	let filename = match path.rfind(['/', '\\']) {
 		Some(pos) if pos + 1 < path.len() => &path[pos + 1..],
 		_ => return Err(Error::msg(format!("No filename found in URL:{url}"))),
 	};
@@ -84,78 +204,375 @@ pub fn extract_cab_file(_data: &[u8], _filename: &str) -> Result<Vec<u8>, Error>
 	Ok(buffer)
 }
 
+/// path of the ESD with sha1 in a cache directory
+pub fn esd_path(cache_directory: &Path, sha1: &str) -> PathBuf {
+	cache_directory.join(format!("{sha1}.esd"))
+}
+
+/// human readable names of the ESDs in a cache directory by sha1, stored in index.json
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CacheIndex {
+	pub names: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl CacheIndex {
+	fn path(cache_directory: &Path) -> PathBuf {
+		cache_directory.join("index.json")
+	}
+
+	pub fn load(cache_directory: &Path) -> Result<Self, Error> {
+		let path = Self::path(cache_directory);
+		if !path.exists() {
+			return Ok(Self::default());
+		}
+		let data = fs::read_to_string(&path)?;
+		serde_json::from_str(&data).map_err(|err| anyhow!("Invalid cache index {path:?}: {err}"))
+	}
+
+	pub fn save(&self, cache_directory: &Path) -> Result<(), Error> {
+		let path = Self::path(cache_directory);
+		let tmp_path = path.with_extension("json.tmp");
+		fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+		fs::rename(tmp_path, path)?;
+		Ok(())
+	}
+
+	/// adds name for sha1 to the index on disk
+	pub fn add(cache_directory: &Path, sha1: &str, name: String) -> Result<(), Error> {
+		let mut index = Self::load(cache_directory)?;
+		if index
+			.names
+			.entry(sha1.to_string())
+			.or_default()
+			.insert(name)
+		{
+			index.save(cache_directory)?;
+		}
+		Ok(())
+	}
+
+	/// removes sha1 from the index on disk
+	pub fn remove(cache_directory: &Path, sha1: &str) -> Result<(), Error> {
+		let mut index = Self::load(cache_directory)?;
+		if index.names.remove(sha1).is_some() {
+			index.save(cache_directory)?;
+		}
+		Ok(())
+	}
+}
+
+/// renames {file}-{lang}-{edition}-{arch}-{sha1}.esd files of older versions to {sha1}.esd,
+/// keeping their name in the index
+pub fn migrate_cache(cache_directory: &Path) -> Result<(), Error> {
+	if !cache_directory.exists() {
+		return Ok(());
+	}
+
+	let mut index = CacheIndex::load(cache_directory)?;
+	let mut migrated = false;
+	for dir_entry in fs::read_dir(cache_directory)? {
+		let path = dir_entry?.path();
+		let file_name = path
+			.file_name()
+			.unwrap_or_default()
+			.to_string_lossy()
+			.to_string();
+		// the ESD itself, an interrupted download or a verified record
+		let Some((stem, suffix)) = [".esd", ".esd.part", ".esd.verified"]
+			.iter()
+			.find_map(|suffix| Some((file_name.strip_suffix(suffix)?, *suffix)))
+		else {
+			continue;
+		};
+		let Some((name, sha1)) = stem.rsplit_once('-') else {
+			continue;
+		};
+		if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+			continue;
+		}
+
+		let target = cache_directory.join(format!("{sha1}{suffix}"));
+		if target.exists() {
+			// same content cached twice
+			fs::remove_file(&path)?;
+		} else {
+			fs::rename(&path, &target)?;
+		}
+		if suffix == ".esd" {
+			eprintln!("Migrated {file_name:?} to {target:?}");
+			index
+				.names
+				.entry(sha1.to_string())
+				.or_default()
+				.insert(name.to_string());
+			migrated = true;
+		}
+	}
+	if migrated {
+		index.save(cache_directory)?;
+	}
+	Ok(())
+}
+
 pub struct WinEsdDownloader {
 	cache_directory: PathBuf,
 	http_client: reqwest::blocking::Client,
+	http: HttpConfig,
+	/// number of parallel connections used for downloading an ESD
+	pub connections: usize,
+	/// only use cached catalogs and ESDs
+	pub offline: bool,
+	/// only use cached catalogs, ESDs may still be downloaded
+	pub frozen: bool,
+	/// age after which a cached catalog is fetched again
+	pub catalog_ttl: Duration,
+	/// always re-hash cached ESDs, even if they have been verified before
+	pub paranoid: bool,
+	/// mirrors tried before those of the config, see Config.mirrors
+	pub mirrors: Vec<String>,
 }
 
 impl WinEsdDownloader {
-	pub fn new(cache_directory: impl AsRef<Path>) -> Result<Self> {
+	pub fn new(cache_directory: impl AsRef<Path>, http: &HttpConfig) -> Result<Self> {
 		let cache_directory = cache_directory.as_ref().to_path_buf();
 		fs::create_dir_all(&cache_directory)?;
 
 		// Download and parse products.xml
-		let client = reqwest::blocking::Client::new();
+		let client = http_client(http)?;
 
 		Ok(Self {
 			cache_directory,
 			http_client: client,
+			http: http.clone(),
+			connections: 1,
+			offline: false,
+			frozen: false,
+			catalog_ttl: Duration::from_secs(24 * 60 * 60),
+			paranoid: false,
+			mirrors: Vec::new(),
 		})
 	}
 
+	pub fn cache_directory(&self) -> &Path {
+		&self.cache_directory
+	}
+
+	/// every file of the catalogs fetched so far
+	pub fn history(&self) -> Result<CatalogHistory, Error> {
+		CatalogHistory::load(&self.cache_directory.join("catalog"))
+	}
+
 	pub fn files(&self, win_ver: &MajorWinVer) -> Result<Vec<FileInfo>, Error> {
+		let xml_str = self.products_xml(win_ver)?;
+		return Ok(find_files(&xml_str)?);
+	}
+
+	/// products.cab the cached catalog was extracted from
+	pub fn catalog_cab_path(&self, win_ver: &MajorWinVer) -> PathBuf {
+		self.cache_directory
+			.join("catalog")
+			.join(format!("products-{}.cab", win_ver.as_str()))
+	}
+
+	/// products.xml of the catalog, cached in {cache_directory}/catalog for catalog_ttl
+	fn products_xml(&self, win_ver: &MajorWinVer) -> Result<String, Error> {
+		let catalog_dir = self.cache_directory.join("catalog");
+		let cab_path = self.catalog_cab_path(win_ver);
+		let xml_path = catalog_dir.join(format!("products-{}.xml", win_ver.as_str()));
+
+		let age = fs::metadata(&xml_path)
+			.and_then(|m| m.modified())
+			.ok()
+			.and_then(|modified| modified.elapsed().ok());
+		if let Some(age) = age {
+			if self.offline || self.frozen || age < self.catalog_ttl {
+				return Ok(fs::read_to_string(&xml_path)?);
+			}
+		} else if self.offline || self.frozen {
+			return Err(anyhow!(
+				"No cached catalog for windows {} found at {xml_path:?}, run once without offline or frozen mode",
+				win_ver.as_str()
+			));
+		}
+
 		let url = match win_ver {
 			MajorWinVer::Win10 => "https://go.microsoft.com/fwlink/?LinkId=841361",
 			MajorWinVer::Win11 => "https://go.microsoft.com/fwlink/?LinkId=2156292",
 		};
-		let response = self.http_client.get(url).send()?.bytes()?;
+		let response = with_retries(&self.http, "Fetching catalog", || {
+			Ok(self
+				.http_client
+				.get(url)
+				.send()?
+				.error_for_status()?
+				.bytes()?)
+		});
+		if let (Err(err), Some(_)) = (&response, age) {
+			eprintln!("Failed to fetch catalog, using outdated cache {xml_path:?}\n{err}");
+			return Ok(fs::read_to_string(&xml_path)?);
+		}
+		let response = response?;
 
 		let xml_bytes = extract_cab_file(&response, "products.xml")?;
-		let xml_str = String::from_utf8(xml_bytes.clone())?;
-		return Ok(find_files(&xml_str)?);
+		let xml_str = String::from_utf8(xml_bytes)?;
+
+		fs::create_dir_all(&catalog_dir)?;
+		fs::write(&cab_path, &response)?;
+		fs::write(&xml_path, &xml_str)?;
+
+		let mut history = CatalogHistory::load(&catalog_dir)?;
+		history.record(win_ver, &find_files(&xml_str)?);
+		history.save()?;
+		Ok(xml_str)
+	}
+
+	/// returns path:PathBuf, sha1size:String, url:String, served_by:String
+	pub fn download(&self, config: &Config) -> Result<(PathBuf, String, String, String), Error> {
+		let (url, expected_sha1, expected_size) = self.pin(config)?;
+		let (path, served_by, _) = self.fetch(config, &url, &expected_sha1, &expected_size)?;
+		Ok((
+			path,
+			format!("{expected_sha1}:{expected_size}"),
+			url,
+			served_by,
+		))
 	}
-	/// returns path:PathBuf, sha1size:String, url:String
-	pub fn download(&self, config: &Config) -> Result<(PathBuf, String, String), Error> {
-		let (expected_size, expected_sha1, url, sha1size): (u64, String, String, String);
 
-		// figure out pinning, sha1, size, url etc.
-		if let Some(cfgurl) = &config.url {
-			// config.url exists
-			url = cfgurl.clone();
+	/// returns url:String, sha1:String, size:u64 of the ESD for config,
+	/// looked up in the catalog if config.url isn't set
+	pub fn pin(&self, config: &Config) -> Result<(String, String, u64), Error> {
+		if let Some(url) = &config.url {
 			let (sha1, size) = config.parse_sha1size()?;
-			sha1size = config.sha1size.clone().unwrap();
-			expected_sha1 = sha1;
-			expected_size = size;
-		} else {
-			// find url
-			let file_info = self.find_file_info(&config)?;
-			url = file_info.file_path;
-			let localsha1size = (file_info.sha1, file_info.size);
-
-			if let Some(expected_sha1size) = config.parse_sha1size().ok() {
-				&localsha1size.expect_equal(
-					expected_sha1size,
-					"Mismatch between config.sha1size and actual file info reported by the endpoint",
+			return Ok((url.clone(), sha1, size));
+		}
+		let (file_info, _) = self.pin_file(config)?;
+		Ok((file_info.file_path, file_info.sha1, file_info.size))
+	}
+
+	/// catalog entry of the ESD for config, from the catalog history if config.sha1size
+	/// pins a build the catalog doesn't list anymore, and whether the current catalog lists it
+	pub fn pin_file(&self, config: &Config) -> Result<(FileInfo, bool), Error> {
+		let mut file_info = self.find_file_info(config);
+		let mut listed = true;
+		if let (Some(build), Some(err)) = (&config.build, file_info.as_ref().err()) {
+			// possibly a build the catalog doesn't list anymore
+			let history = self.history()?;
+			let entry = history.find_build(
+				&config.version,
+				build,
+				&config.lang,
+				&config.edition,
+				&config.arch,
+			);
+			match entry {
+				Some(entry) => {
+					eprintln!(
+						"Build {build} isn't listed in the current catalog anymore, using it from the catalog history"
+					);
+					file_info = Ok(entry.file.clone());
+					listed = false;
+				}
+				None => {
+					return Err(anyhow!(
+						"{err}\nThe catalog history hasn't seen build {build} either"
+					));
+				}
+			}
+		}
+		if let Ok((sha1, _)) = config.parse_sha1size() {
+			// pinned, possibly to a build the catalog doesn't list anymore
+			let pinned = file_info
+				.as_ref()
+				.is_ok_and(|file| file.sha1.eq_ignore_ascii_case(&sha1));
+			if !pinned {
+				let history = self.history()?;
+				let entry = history.find(
+					&config.version,
+					&sha1,
+					&config.lang,
+					&config.edition,
+					&config.arch,
+				);
+				if let Some(entry) = entry {
+					eprintln!(
+						"{sha1} isn't listed in the current catalog anymore, using build {} from the catalog history",
+						entry.build
+					);
+					file_info = Ok(entry.file.clone());
+					listed = false;
+				}
+			}
+		}
+		let file_info = file_info?;
+		if let Ok(expected_sha1size) = config.parse_sha1size() {
+			(file_info.sha1.clone(), file_info.size).expect_equal(
+				expected_sha1size,
+				"Mismatch between config.sha1size and actual file info reported by the endpoint",
+			)?;
+		}
+		Ok((file_info, listed))
+	}
+
+	/// ESD for url from the cache, downloaded from config.mirrors or url if it isn't cached.
+	/// Verified against config.pin as well, if provided
+	///
+	/// returns path:PathBuf, served_by:String, sha256:String
+	pub fn fetch(
+		&self,
+		config: &Config,
+		url: &String,
+		expected_sha1: &str,
+		expected_size: &u64,
+	) -> Result<(PathBuf, String, String), Error> {
+		let (expected_sha1, expected_size) = (expected_sha1.to_string(), *expected_size);
+		let sha1size = format!("{expected_sha1}:{expected_size}");
+		let file_name = filename_without_extension(url)?;
+		let expected_sha256 = match config.parse_pin()? {
+			Some((sha256, size)) => {
+				size.expect_equal(
+					expected_size,
+					"Mismatch between the sizes of config.pin and config.sha1size",
 				)?;
+				Some(sha256)
 			}
-			expected_sha1 = localsha1size.0;
-			expected_size = localsha1size.1;
-			sha1size = format!("{expected_sha1}:{expected_size}")
+			None => None,
 		};
-		let file_name = filename_without_extension(&url)?;
-
-		let cache_file_name = format!(
-			"{}-{}-{}-{}-{}.esd",
-			file_name,
-			config.lang,
-			config.edition,
-			config.arch.as_str(),
-			expected_sha1
-		);
 
-		let cache_file_path = &self.cache_directory.join(cache_file_name);
+		// content-addressed, so that configs for different editions share multi-edition ESDs
+		migrate_cache(&self.cache_directory)?;
+		let cache_file_path = &esd_path(&self.cache_directory, &expected_sha1);
+		let cached = |served_by: String, digests: Digests| {
+			let name = format!(
+				"{}-{}-{}-{}",
+				file_name,
+				config.lang,
+				config.edition,
+				config.arch.as_str()
+			);
+			CacheIndex::add(&self.cache_directory, &expected_sha1, name)?;
+			Ok((cache_file_path.to_path_buf(), served_by, digests.sha256))
+		};
+		// the file is named by its sha1, a sha1 match with another sha256 is a wrong pin
+		let check_pin = |digests: &Digests| match &expected_sha256 {
+			Some(sha256) if *sha256 != digests.sha256 => Err(anyhow!(
+				"{cache_file_path:?} matches sha1 {expected_sha1} but not config.pin\nGot sha256:{}\nExpected sha256:{sha256}",
+				digests.sha256
+			)),
+			_ => Ok(()),
+		};
 
 		// Check if file exists and verify hash
+		let served_by = config.served_by.clone().unwrap_or(url.clone());
+		if cache_file_path.exists()
+			&& !self.paranoid
+			&& let Some(digests) = verified_digests(cache_file_path, &expected_sha1)
+		{
+			// verified before and not modified since
+			check_pin(&digests)?;
+			mark_used(cache_file_path);
+			return cached(served_by, digests);
+		}
 		if cache_file_path.exists() {
 			// check size missmatch first
 			let existing_size = fs::metadata(cache_file_path)?.len();
@@ -163,14 +580,17 @@ impl WinEsdDownloader {
 				eprintln!(
 					"Found existing modified or corrupted file: {cache_file_path:?}.Got size:{existing_size}\nExpected:{expected_size}\n Deleting and downloading again.",
 				);
-				fs::remove_file(&cache_file_path)?;
+				fs::remove_file(cache_file_path)?;
+				let _ = fs::remove_file(verified_path(cache_file_path));
 			} else {
 				// verify existing hash
 				let res = fdownload(
-					File::open(&cache_file_path)?,
+					File::open(cache_file_path)?,
 					None,
+					&0,
 					&expected_size,
-					&expected_sha1,
+					Some(&expected_sha1),
+					None,
 					format!(
 						"Verifying (hashing) {:?}\n",
 						cache_file_path.file_name().unwrap()
@@ -178,34 +598,119 @@ impl WinEsdDownloader {
 					.as_str(),
 				);
 
-				if let Err(err) = res {
+				if let Err(err) = &res {
 					println!("Failed to verify existing file\n:{err}");
-					fs::remove_file(&cache_file_path)?;
+					fs::remove_file(cache_file_path)?;
+					let _ = fs::remove_file(verified_path(cache_file_path));
 				} else {
-					return Ok((cache_file_path.to_path_buf(), sha1size, url));
+					let digests = res?;
+					write_verified(cache_file_path, &digests)?;
+					check_pin(&digests)?;
+					mark_used(cache_file_path);
+					return cached(served_by, digests);
 				}
 			}
 		}
 
-		download_from_url(&url, cache_file_path, &expected_size, &expected_sha1)?;
+		// mirrors first, url as last resort
+		let mut candidates: Vec<String> = Vec::new();
+		for candidate in self.mirrors.iter().chain(&config.mirrors).chain([url]) {
+			// e.g. http://host:8080/{sha1}.esd for a `rinb cache serve` mirror
+			let candidate = candidate.replace("{sha1}", &expected_sha1);
+			if !candidates.contains(&candidate) {
+				candidates.push(candidate);
+			}
+		}
 
-		Ok((cache_file_path.to_path_buf(), sha1size, url))
+		for candidate in &candidates {
+			let res = if let Some(path) = local_path(candidate) {
+				copy_from_path(&path, cache_file_path, &expected_size, &expected_sha1)
+			} else if self.offline {
+				Err(anyhow!(
+					"{cache_file_path:?} is not cached, can't download it in offline mode"
+				))
+			} else {
+				download_from_url(
+					&self.http_client,
+					&self.http,
+					candidate,
+					cache_file_path,
+					&expected_size,
+					&expected_sha1,
+					self.connections,
+				)
+			};
+			if let Err(err) = &res {
+				eprintln!("Failed to download from {candidate}:\n{err}");
+			} else {
+				let digests = res?;
+				write_verified(cache_file_path, &digests)?;
+				check_pin(&digests)?;
+				let served_by = candidate.to_string();
+				return cached(served_by, digests);
+			}
+		}
+
+		Err(anyhow!(
+			"Failed to download {sha1size} from any of {} candidate URLs",
+			candidates.len()
+		))
 	}
 
-	fn find_file_info(&self, config: &Config) -> Result<FileInfo, Error> {
+	pub fn find_file_info(&self, config: &Config) -> Result<FileInfo, Error> {
 		let files = self.files(&config.version)?;
 
 		let matching_files: Vec<FileInfo> = files
+			.iter()
+			.filter(|file| {
+				file.matches(
+					Some(&config.lang),
+					Some(&config.edition),
+					Some(&config.arch),
+				)
+			})
+			.cloned()
+			.collect();
+
+		// the most specific catalog edition listing the SKU, e.g. Core over Professional for Home
+		let editions = catalog_editions(&config.edition);
+		let preferred = editions.iter().find(|edition| {
+			matching_files
+				.iter()
+				.any(|file| file.edition.eq_ignore_ascii_case(edition))
+		});
+		let matching_files: Vec<FileInfo> = matching_files
 			.into_iter()
 			.filter(|file| {
-				file.language_code.eq_ignore_ascii_case(&config.lang)
-					&& file.edition.eq_ignore_ascii_case(&config.edition)
-					&& file.architecture.eq_ignore_ascii_case(config.arch.as_str())
+				preferred.is_some_and(|edition| file.edition.eq_ignore_ascii_case(edition))
 			})
 			.collect();
 
+		let matching_files = match &config.build {
+			Some(build) => {
+				let builds: Vec<String> = matching_files.iter().map(|file| file.build()).collect();
+				let matching_files: Vec<FileInfo> = matching_files
+					.into_iter()
+					.filter(|file| file.build() == *build)
+					.collect();
+				if matching_files.is_empty() && !builds.is_empty() {
+					return Err(anyhow!(
+						"Build {build} isn't listed in the catalog for language: {}, edition: {}, architecture: {}, version:{}. It lists {}",
+						config.lang,
+						config.edition,
+						config.arch.as_str(),
+						config.version.as_str(),
+						builds.join(", ")
+					));
+				}
+				matching_files
+			}
+			None => matching_files,
+		};
+
 		let file = match matching_files.len() {
 			0 => {
+				check_fields(config, &files)?;
 				return Err(anyhow!(
 					"No matching file found for language: {}, edition: {}, architecture: {}, version:{}",
 					config.lang,
@@ -229,3 +734,59 @@ impl WinEsdDownloader {
 		Ok(file)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::TmpDir;
+
+	#[test]
+	fn migrate_cache_renames_to_sha1() {
+		let tmp = TmpDir::new().unwrap();
+		let dir = tmp.path.as_path();
+		let sha1 = "3efed3b29ad7923d7c1e362ad9ef69297cf1f385";
+		let other = "0123456789abcdef0123456789abcdef01234567";
+		let name = "26100.4349-en-us-Professional-amd64";
+		fs::write(dir.join(format!("{name}-{sha1}.esd")), "esd").unwrap();
+		fs::write(dir.join(format!("{name}-{sha1}.esd.verified")), "{}").unwrap();
+		fs::write(dir.join(format!("{name}-{other}.esd.part")), "part").unwrap();
+		// already migrated, the old copy is dropped
+		fs::write(dir.join(format!("old-{other}.esd")), "old").unwrap();
+		fs::write(esd_path(dir, other), "new").unwrap();
+		// not an ESD of an older version
+		fs::write(dir.join("notes-on.esd"), "").unwrap();
+
+		migrate_cache(dir).unwrap();
+
+		assert_eq!(fs::read_to_string(esd_path(dir, sha1)).unwrap(), "esd");
+		assert!(dir.join(format!("{sha1}.esd.verified")).exists());
+		assert!(dir.join(format!("{other}.esd.part")).exists());
+		assert_eq!(fs::read_to_string(esd_path(dir, other)).unwrap(), "new");
+		assert!(!dir.join(format!("old-{other}.esd")).exists());
+		assert!(dir.join("notes-on.esd").exists());
+
+		let index = CacheIndex::load(dir).unwrap();
+		assert_eq!(index.names[sha1], BTreeSet::from([name.to_string()]));
+		assert_eq!(index.names[other], BTreeSet::from(["old".to_string()]));
+	}
+
+	#[test]
+	fn build_from_file_name() {
+		assert_eq!(
+			parse_build(
+				"26100.4349.250607-1500.ge_release_svc_refresh_CLIENTCONSUMER_RET_x64FRE_en-us.esd"
+			),
+			"26100.4349"
+		);
+		assert_eq!(parse_build("19045.2965.230505-1139.esd"), "19045.2965");
+		// no revision
+		assert_eq!(parse_build("22000.ge_release.esd"), "22000");
+		assert_eq!(parse_build("install.esd"), "");
+		assert_eq!(parse_build(""), "");
+	}
+
+	#[test]
+	fn migrate_missing_cache() {
+		migrate_cache(Path::new("/nonexistent/rinb/cache")).unwrap();
+	}
+}
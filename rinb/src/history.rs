@@ -0,0 +1,185 @@
+use std::{
+	cmp::Reverse,
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Error, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Arch, MajorWinVer};
+use crate::edition::catalog_editions;
+use crate::esd_downloader::FileInfo;
+
+/// a file listed by some catalog fetched in the past
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+	pub version: MajorWinVer,
+	/// e.g. 26100.4349
+	pub build: String,
+	/// unix timestamp of the first catalog listing this file
+	pub first_seen: u64,
+	/// unix timestamp of the last catalog listing this file
+	pub last_seen: u64,
+	#[serde(flatten)]
+	pub file: FileInfo,
+}
+
+/// every file of every catalog rinb parsed, stored in {catalog_dir}/history.json.
+///
+/// Microsoft only lists the latest build, this keeps pinned older builds resolvable.
+pub struct CatalogHistory {
+	path: PathBuf,
+	pub entries: Vec<HistoryEntry>,
+}
+
+impl CatalogHistory {
+	pub fn load(catalog_dir: &Path) -> Result<Self, Error> {
+		let path = catalog_dir.join("history.json");
+		let entries = if path.exists() {
+			let data = fs::read_to_string(&path)?;
+			serde_json::from_str(&data)
+				.map_err(|err| anyhow!("Invalid catalog history {path:?}: {err}"))?
+		} else {
+			Vec::new()
+		};
+		Ok(Self { path, entries })
+	}
+
+	pub fn save(&self) -> Result<(), Error> {
+		if let Some(parent) = self.path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let tmp_path = self.path.with_extension("json.tmp");
+		fs::write(&tmp_path, serde_json::to_string_pretty(&self.entries)?)?;
+		fs::rename(tmp_path, &self.path)?;
+		Ok(())
+	}
+
+	/// adds the files of a freshly fetched catalog
+	pub fn record(&mut self, version: &MajorWinVer, files: &[FileInfo]) {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+
+		// the same ESD is listed once per edition
+		let key = |version: &MajorWinVer, file: &FileInfo| {
+			(
+				*version,
+				file.sha1.clone(),
+				file.language_code.clone(),
+				file.edition.clone(),
+				file.architecture.clone(),
+			)
+		};
+		let mut known: HashMap<_, usize> = self
+			.entries
+			.iter()
+			.enumerate()
+			.map(|(index, e)| (key(&e.version, &e.file), index))
+			.collect();
+
+		for file in files {
+			match known.get(&key(version, file)) {
+				Some(index) => self.entries[*index].last_seen = now,
+				None => {
+					known.insert(key(version, file), self.entries.len());
+					self.entries.push(HistoryEntry {
+						version: *version,
+						build: file.build(),
+						first_seen: now,
+						last_seen: now,
+						file: file.clone(),
+					});
+				}
+			}
+		}
+	}
+
+	/// file of version and build matching lang, edition and arch, of the most specific
+	/// catalog edition including edition and the most recently listed one of those
+	pub fn find_build(
+		&self,
+		version: &MajorWinVer,
+		build: &str,
+		lang: &str,
+		edition: &str,
+		arch: &Arch,
+	) -> Option<&HistoryEntry> {
+		let catalog = catalog_editions(edition);
+		self.entries
+			.iter()
+			.filter(|e| &e.version == version && e.build == build)
+			.filter(|e| e.file.matches(Some(lang), Some(edition), Some(arch)))
+			.min_by_key(|e| {
+				let specificity = catalog
+					.iter()
+					.position(|listed| listed.eq_ignore_ascii_case(&e.file.edition));
+				(specificity, Reverse(e.last_seen))
+			})
+	}
+
+	/// file of version with sha1 matching lang, edition and arch
+	pub fn find(
+		&self,
+		version: &MajorWinVer,
+		sha1: &str,
+		lang: &str,
+		edition: &str,
+		arch: &Arch,
+	) -> Option<&HistoryEntry> {
+		self.entries
+			.iter()
+			.filter(|e| &e.version == version && e.file.sha1.eq_ignore_ascii_case(sha1))
+			.find(|e| e.file.matches(Some(lang), Some(edition), Some(arch)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(edition: &str, last_seen: u64) -> HistoryEntry {
+		HistoryEntry {
+			version: MajorWinVer::Win11,
+			build: "26100.4349".to_string(),
+			first_seen: 0,
+			last_seen,
+			file: FileInfo {
+				file_name: format!("26100.4349.x64fre.{edition}.esd"),
+				language_code: "en-us".to_string(),
+				language: "English".to_string(),
+				edition: edition.to_string(),
+				architecture: "x64".to_string(),
+				size: 1,
+				sha1: edition.to_lowercase(),
+				file_path: String::new(),
+			},
+		}
+	}
+
+	#[test]
+	fn find_build_prefers_most_specific_catalog_edition() {
+		let history = CatalogHistory {
+			path: PathBuf::new(),
+			entries: vec![entry("Core", 1), entry("Professional", 2)],
+		};
+		let find = |edition| {
+			history
+				.find_build(
+					&MajorWinVer::Win11,
+					"26100.4349",
+					"en-us",
+					edition,
+					&Arch::Amd64,
+				)
+				.map(|e| e.file.edition.as_str())
+		};
+		assert_eq!(find("Home"), Some("Core"));
+		assert_eq!(find("Pro"), Some("Professional"));
+		assert_eq!(find("Education"), Some("Professional"));
+	}
+}
@@ -0,0 +1,518 @@
+use std::{
+	collections::HashSet,
+	env,
+	fs::{self, File},
+	io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Error, Result, anyhow};
+use hex::ToHex;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::download::{fdownload, local_path, recorded_digests, write_verified};
+use crate::esd::EsdFile;
+use crate::utils::ExpectEqual;
+
+// see ECMA-167, only the parts used by UDF 1.02 (as written by oscdimg) are supported
+const SECTOR_SIZE: u64 = 2048;
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_ALLOCATION_EXTENT: u16 = 258;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+/// len bytes of buf at offset, an error if the image is truncated or corrupt
+fn bytes_at(buf: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+	offset
+		.checked_add(len)
+		.and_then(|end| buf.get(offset..end))
+		.ok_or_else(|| {
+			anyhow!(
+				"UDF structure at {offset}+{len} exceeds its {} bytes",
+				buf.len()
+			)
+		})
+}
+
+fn u8_at(buf: &[u8], offset: usize) -> Result<u8, Error> {
+	Ok(bytes_at(buf, offset, 1)?[0])
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Result<u16, Error> {
+	Ok(u16::from_le_bytes(bytes_at(buf, offset, 2)?.try_into()?))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Result<u32, Error> {
+	Ok(u32::from_le_bytes(bytes_at(buf, offset, 4)?.try_into()?))
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> Result<u64, Error> {
+	Ok(u64::from_le_bytes(bytes_at(buf, offset, 8)?.try_into()?))
+}
+
+/// OSTA compressed unicode (d-string without length byte)
+fn decode_name(raw: &[u8]) -> Result<String, Error> {
+	match raw.first() {
+		Some(8) => Ok(raw[1..].iter().map(|&c| c as char).collect()),
+		Some(16) => {
+			let wide: Vec<u16> = raw[1..]
+				.chunks_exact(2)
+				.map(|c| u16::from_be_bytes([c[0], c[1]]))
+				.collect();
+			Ok(String::from_utf16(&wide)?)
+		}
+		_ => Err(anyhow!("Unsupported UDF file identifier encoding")),
+	}
+}
+
+struct Extent {
+	/// absolute block
+	block: u64,
+	len: u64,
+	recorded: bool,
+}
+
+struct FileEntry {
+	is_dir: bool,
+	size: u64,
+	extents: Vec<Extent>,
+	/// data stored inside the file entry itself
+	embedded: Option<Vec<u8>>,
+}
+
+pub struct IsoEntry {
+	/// path relative to the image root, '/' separated
+	pub path: String,
+	pub is_dir: bool,
+	pub size: u64,
+	icb: u64,
+}
+
+pub struct IsoFile<'a> {
+	pub path: &'a PathBuf,
+	file: File,
+	block_size: u64,
+	partition_start: u64,
+	root_icb: u64,
+}
+
+impl<'a> IsoFile<'a> {
+	pub fn new(path: &'a PathBuf) -> Result<Self, Error> {
+		let file = File::open(path)?;
+		let mut iso = Self {
+			path,
+			file,
+			block_size: SECTOR_SIZE,
+			partition_start: 0,
+			root_icb: 0,
+		};
+
+		let anchor = iso.read_sector(256)?;
+		if u16_at(&anchor, 0)? != TAG_ANCHOR {
+			return Err(anyhow!(
+				"{path:?} has no UDF file system, ISO9660-only images are not supported"
+			));
+		}
+		let (vds_len, vds_location) = (u32_at(&anchor, 16)? as u64, u32_at(&anchor, 20)? as u64);
+
+		// volume descriptor sequence
+		let (mut partition_start, mut fsd) = (None, None);
+		for sector in vds_location..vds_location + vds_len / SECTOR_SIZE {
+			let desc = iso.read_sector(sector)?;
+			match u16_at(&desc, 0)? {
+				TAG_PARTITION => partition_start = Some(u32_at(&desc, 188)? as u64),
+				TAG_LOGICAL_VOLUME => {
+					iso.block_size = u32_at(&desc, 212)? as u64;
+					if !(512..=65536).contains(&iso.block_size) {
+						return Err(anyhow!("Invalid UDF block size {}", iso.block_size));
+					}
+					// file set descriptor location (long_ad)
+					fsd = Some(u32_at(&desc, 252)? as u64);
+					let map_count = u32_at(&desc, 268)?;
+					map_count.expect_equal(1, "Unsupported number of UDF partition maps")?;
+					u8_at(&desc, 440)?.expect_equal(1, "Unsupported UDF partition map type")?;
+				}
+				TAG_TERMINATING => break,
+				_ => {}
+			}
+		}
+		let partition_start = partition_start.ok_or_else(|| anyhow!("No UDF partition found"))?;
+		let fsd = fsd.ok_or_else(|| anyhow!("No UDF logical volume found"))?;
+		iso.partition_start = partition_start;
+
+		let fsd = iso.read_block(partition_start + fsd)?;
+		u16_at(&fsd, 0)?.expect_equal(TAG_FILE_SET, "Expected UDF file set descriptor")?;
+		// root directory ICB (long_ad)
+		iso.root_icb = partition_start + u32_at(&fsd, 404)? as u64;
+		Ok(iso)
+	}
+
+	fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+		let mut buf = vec![0; len];
+		(&self.file).seek(SeekFrom::Start(offset))?;
+		(&self.file).read_exact(&mut buf)?;
+		Ok(buf)
+	}
+
+	fn read_sector(&self, sector: u64) -> Result<Vec<u8>, Error> {
+		self.read_at(sector * SECTOR_SIZE, SECTOR_SIZE as usize)
+	}
+
+	fn read_block(&self, block: u64) -> Result<Vec<u8>, Error> {
+		self.read_at(block * self.block_size, self.block_size as usize)
+	}
+
+	/// parses short_ad or long_ad allocation descriptors, following continuation extents
+	fn read_extents(&self, mut ads: Vec<u8>, long: bool) -> Result<Vec<Extent>, Error> {
+		let ad_len = if long { 16 } else { 8 };
+		let mut extents = Vec::new();
+		let mut offset = 0;
+		while offset + ad_len <= ads.len() {
+			let raw_len = u32_at(&ads, offset)?;
+			let (len, kind) = ((raw_len & 0x3FFF_FFFF) as u64, raw_len >> 30);
+			let block = self.partition_start + u32_at(&ads, offset + 4)? as u64;
+			offset += ad_len;
+			if len == 0 {
+				break;
+			}
+			if kind == 3 {
+				// next extent of allocation descriptors
+				let aed = self.read_block(block)?;
+				u16_at(&aed, 0)?
+					.expect_equal(TAG_ALLOCATION_EXTENT, "Expected UDF allocation extent")?;
+				let ads_len = u32_at(&aed, 20)? as usize;
+				ads = bytes_at(&aed, 24, ads_len)?.to_vec();
+				offset = 0;
+				continue;
+			}
+			extents.push(Extent {
+				block,
+				len,
+				recorded: kind == 0,
+			});
+		}
+		Ok(extents)
+	}
+
+	fn file_entry(&self, icb: u64) -> Result<FileEntry, Error> {
+		let fe = self.read_block(icb)?;
+		let (ea_len, ad_len, ad_start) = match u16_at(&fe, 0)? {
+			TAG_FILE_ENTRY => (u32_at(&fe, 168)?, u32_at(&fe, 172)?, 176),
+			TAG_EXTENDED_FILE_ENTRY => (u32_at(&fe, 208)?, u32_at(&fe, 212)?, 216),
+			tag => {
+				return Err(anyhow!(
+					"Expected UDF file entry at block {icb}, got tag {tag}"
+				));
+			}
+		};
+		let ads_start = ad_start + ea_len as usize;
+		let ads = bytes_at(&fe, ads_start, ad_len as usize)?.to_vec();
+
+		let is_dir = u8_at(&fe, 27)? == 4;
+		let size = u64_at(&fe, 56)?;
+		let (extents, embedded) = match u16_at(&fe, 34)? & 7 {
+			0 => (self.read_extents(ads, false)?, None),
+			1 => (self.read_extents(ads, true)?, None),
+			3 => (Vec::new(), Some(ads)),
+			kind => return Err(anyhow!("Unsupported UDF allocation descriptor type {kind}")),
+		};
+		Ok(FileEntry {
+			is_dir,
+			size,
+			extents,
+			embedded,
+		})
+	}
+
+	fn copy_to<W: Write>(&self, entry: &FileEntry, writer: &mut W) -> Result<(), Error> {
+		if let Some(data) = &entry.embedded {
+			writer.write_all(bytes_at(data, 0, entry.size as usize)?)?;
+			return Ok(());
+		}
+		let mut remaining = entry.size;
+		for extent in &entry.extents {
+			let len = extent.len.min(remaining);
+			if extent.recorded {
+				(&self.file).seek(SeekFrom::Start(extent.block * self.block_size))?;
+				io::copy(&mut (&self.file).take(len), writer)?;
+			} else {
+				io::copy(&mut io::repeat(0).take(len), writer)?;
+			}
+			remaining -= len;
+		}
+		remaining.expect_equal(0, "UDF file entry shorter than its size")?;
+		Ok(())
+	}
+
+	/// all files and directories, recursively
+	pub fn entries(&self) -> Result<Vec<IsoEntry>, Error> {
+		let mut result = Vec::new();
+		let mut stack = vec![(String::new(), self.root_icb)];
+		// malformed images may link a directory into itself
+		let mut visited = HashSet::from([self.root_icb]);
+
+		while let Some((dir_path, icb)) = stack.pop() {
+			let dir = self.file_entry(icb)?;
+			let mut data = Vec::new();
+			self.copy_to(&dir, &mut data)?;
+
+			let mut offset = 0;
+			while offset + 38 <= data.len() {
+				u16_at(&data, offset)?
+					.expect_equal(TAG_FILE_IDENTIFIER, "Expected UDF file identifier")?;
+				let characteristics = u8_at(&data, offset + 18)?;
+				let name_len = u8_at(&data, offset + 19)? as usize;
+				let child_icb = self.partition_start + u32_at(&data, offset + 24)? as u64;
+				let impl_len = u16_at(&data, offset + 36)? as usize;
+				let raw_name = bytes_at(&data, offset + 38 + impl_len, name_len)?;
+				offset += (38 + impl_len + name_len + 3) & !3;
+
+				// skip deleted and parent entries
+				if characteristics & (0x4 | 0x8) != 0 {
+					continue;
+				}
+				let name = decode_name(raw_name)?;
+				let path = if dir_path.is_empty() {
+					name
+				} else {
+					format!("{dir_path}/{name}")
+				};
+
+				let child = self.file_entry(child_icb)?;
+				if child.is_dir {
+					if !visited.insert(child_icb) {
+						return Err(anyhow!(
+							"UDF directory {path} at block {child_icb} is listed twice"
+						));
+					}
+					stack.push((path.clone(), child_icb));
+				}
+				result.push(IsoEntry {
+					path,
+					is_dir: child.is_dir,
+					size: child.size,
+					icb: child_icb,
+				});
+			}
+		}
+		Ok(result)
+	}
+
+	pub fn extract(&self, entry: &IsoEntry, dest: &PathBuf) -> Result<(), Error> {
+		if entry.is_dir {
+			fs::create_dir_all(dest)?;
+			return Ok(());
+		}
+		if let Some(parent) = dest.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut writer = BufWriter::new(File::create(dest)?);
+		self.copy_to(&self.file_entry(entry.icb)?, &mut writer)?;
+		writer.flush()?;
+		Ok(())
+	}
+
+	/// extracts the media to target_dir, keeping only the install image matching edition
+	pub fn install_dir(
+		&self,
+		target_dir: &Path,
+		edition: &str,
+		max_file_size: &u64,
+	) -> Result<(), Error> {
+		let entries = self.entries()?;
+		let install = entries
+			.iter()
+			.find(|e| {
+				e.path.eq_ignore_ascii_case("sources/install.wim")
+					|| e.path.eq_ignore_ascii_case("sources/install.esd")
+			})
+			.ok_or_else(|| {
+				anyhow!(
+					"No sources/install.wim or sources/install.esd in {:?}",
+					self.path
+				)
+			})?;
+
+		for entry in &entries {
+			if entry.path != install.path {
+				self.extract(entry, &target_dir.join(&entry.path))?;
+			}
+		}
+
+		// wimlib needs a file to open
+		let tmppath: &PathBuf = &env::temp_dir().join(format!(
+			"rinb_tmp_file_{}.wim",
+			Uuid::new_v4().encode_hex::<String>()
+		));
+		let result = (|| -> Result<()> {
+			self.extract(install, tmppath)?;
+			let install_wim = EsdFile::new(tmppath)?;
+			let image = install_wim.image_by_edition(1, edition)?.ok_or_else(|| {
+				anyhow!(
+					"No install image with edition {edition} found in {:?} ({} images)",
+					self.path,
+					install_wim.info.image_count
+				)
+			})?;
+			install_wim.write(&image, &target_dir.join(&install.path), max_file_size)?;
+			Ok(())
+		})();
+		// ensure cleanup, without hiding the error of result
+		let _ = fs::remove_file(tmppath);
+		result
+	}
+}
+
+/// Resolves config.iso to a local path and verifies it against config.sha1size and config.pin
+/// returns path:PathBuf, sha1size:String, pin:String
+pub fn verify_iso(config: &Config) -> Result<(PathBuf, String, String), Error> {
+	let iso = config
+		.iso
+		.as_ref()
+		.ok_or_else(|| anyhow!("config.iso not provided"))?;
+	let path =
+		local_path(iso).ok_or_else(|| anyhow!("config.iso must be a local path or file:// URL"))?;
+
+	let size = fs::metadata(&path)?.len();
+	let mut expected_sha1 = None;
+	if let Ok((sha1, expected_size)) = config.parse_sha1size() {
+		size.expect_equal(expected_size, format!("Size mismatch for {path:?}"))?;
+		expected_sha1 = Some(sha1);
+	}
+	let mut expected_sha256 = None;
+	if let Some((sha256, expected_size)) = config.parse_pin()? {
+		size.expect_equal(expected_size, format!("Size mismatch for {path:?}"))?;
+		expected_sha256 = Some(sha256);
+	}
+
+	// verified before and not modified since
+	let recorded = recorded_digests(&path).filter(|digests| {
+		expected_sha1
+			.as_ref()
+			.is_none_or(|sha1| *sha1 == digests.sha1)
+			&& expected_sha256
+				.as_ref()
+				.is_none_or(|sha256| *sha256 == digests.sha256)
+	});
+	let digests = match recorded {
+		Some(digests) => digests,
+		None => {
+			let digests = fdownload(
+				File::open(&path)?,
+				None,
+				&0,
+				&size,
+				expected_sha1.as_deref(),
+				expected_sha256.as_deref(),
+				&format!("Verifying (hashing) {path:?}\n"),
+			)?;
+			// next to the ISO, which may be read-only
+			if let Err(err) = write_verified(&path, &digests) {
+				eprintln!("Failed to record the digests of {path:?}: {err}");
+			}
+			digests
+		}
+	};
+	Ok((
+		path,
+		format!("{}:{size}", digests.sha1),
+		format!("sha256:{}:{size}", digests.sha256),
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::TmpDir;
+
+	fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+		image[offset..offset + bytes.len()].copy_from_slice(bytes);
+	}
+
+	/// minimal UDF image whose root file entry has extended attributes of ea_len bytes
+	fn image(ea_len: u32) -> Vec<u8> {
+		let sector = SECTOR_SIZE as usize;
+		let mut image = vec![0; 262 * sector];
+		let anchor = 256 * sector;
+		put(&mut image, anchor, &TAG_ANCHOR.to_le_bytes());
+		put(
+			&mut image,
+			anchor + 16,
+			&(3 * SECTOR_SIZE as u32).to_le_bytes(),
+		);
+		put(&mut image, anchor + 20, &257u32.to_le_bytes());
+
+		put(&mut image, 257 * sector, &TAG_PARTITION.to_le_bytes());
+		let volume = 258 * sector;
+		put(&mut image, volume, &TAG_LOGICAL_VOLUME.to_le_bytes());
+		put(
+			&mut image,
+			volume + 212,
+			&(SECTOR_SIZE as u32).to_le_bytes(),
+		);
+		put(&mut image, volume + 252, &260u32.to_le_bytes());
+		put(&mut image, volume + 268, &1u32.to_le_bytes());
+		image[volume + 440] = 1;
+		put(&mut image, 259 * sector, &TAG_TERMINATING.to_le_bytes());
+
+		put(&mut image, 260 * sector, &TAG_FILE_SET.to_le_bytes());
+		put(&mut image, 260 * sector + 404, &261u32.to_le_bytes());
+
+		let root = 261 * sector;
+		put(&mut image, root, &TAG_FILE_ENTRY.to_le_bytes());
+		image[root + 27] = 4;
+		put(&mut image, root + 168, &ea_len.to_le_bytes());
+		// embedded, empty directory
+		put(&mut image, root + 34, &3u16.to_le_bytes());
+		image
+	}
+
+	fn entries(image: &[u8]) -> Result<Vec<IsoEntry>, Error> {
+		let tmp = TmpDir::new()?;
+		let path = tmp.path.join("image.iso");
+		fs::write(&path, image)?;
+		IsoFile::new(&path)?.entries()
+	}
+
+	#[test]
+	fn empty_image() {
+		assert!(entries(&image(0)).unwrap().is_empty());
+	}
+
+	#[test]
+	fn directory_cycle() {
+		let mut image = image(0);
+		// the root directory lists itself as subdirectory a
+		let root = 261 * SECTOR_SIZE as usize;
+		let mut identifier = vec![0; 40];
+		put(&mut identifier, 0, &TAG_FILE_IDENTIFIER.to_le_bytes());
+		identifier[18] = 2;
+		identifier[19] = 2;
+		put(&mut identifier, 24, &261u32.to_le_bytes());
+		put(&mut identifier, 38, &[8, b'a']);
+		put(&mut image, root + 56, &40u64.to_le_bytes());
+		put(&mut image, root + 172, &40u32.to_le_bytes());
+		put(&mut image, root + 176, &identifier);
+
+		let err = entries(&image).err().unwrap();
+		assert!(err.to_string().contains("listed twice"), "{err}");
+	}
+
+	#[test]
+	fn corrupt_file_entry() {
+		let err = entries(&image(0xFFFF_FFF0)).err().unwrap();
+		assert!(err.to_string().contains("exceeds"), "{err}");
+	}
+
+	#[test]
+	fn truncated_image() {
+		assert!(entries(&image(0)[..257 * SECTOR_SIZE as usize]).is_err());
+		assert!(entries(&[]).is_err());
+	}
+}
@@ -1,6 +1,15 @@
 mod download;
 mod utils;
 
+pub mod cache;
 pub mod config;
+pub mod edition;
 pub mod esd_downloader;
 pub mod esd;
+pub mod history;
+pub mod iso;
+pub mod lock;
+pub mod profile;
+pub mod remote_cache;
+pub mod serve;
+pub mod source;
@@ -0,0 +1,301 @@
+use std::{
+	collections::BTreeMap,
+	fs::{self, File},
+	io,
+	path::Path,
+};
+
+use anyhow::{Error, Result, anyhow};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::esd_downloader::FileInfo;
+use crate::profile::Override;
+use crate::source::Provenance;
+
+/// version of the lock file format written by this rinb.
+///
+/// 0: the resolved config only
+/// 1: with resolution
+/// 2: one lock per profile for configs with profiles
+pub const LOCK_VERSION: u32 = 2;
+
+/// fields selecting the image, the lock is stale if the config changes one of them
+const SELECTING: [&str; 6] = ["version", "build", "lang", "edition", "arch", "iso"];
+/// fields written to the lock by resolving
+const PINNED: [&str; 3] = ["url", "sha1size", "pin"];
+
+/// how the artifact of a lock was resolved, for audits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resolution {
+	/// version of rinb which resolved it
+	pub rinb_version: String,
+	/// RFC 3339 timestamp
+	pub resolved_at: String,
+	/// name of the source which resolved the artifact
+	pub source: String,
+	/// sha256 of the products.cab the ESD was looked up in
+	pub catalog_sha256: Option<String>,
+	/// catalog entry of the ESD
+	pub file: Option<FileInfo>,
+	/// windows build read from the image, e.g. 10.0.26100.4349
+	pub build: Option<String>,
+	/// sha256 of each produced artifact, by path
+	#[serde(default)]
+	pub artifacts: BTreeMap<String, String>,
+	/// fields set by --set or RINB_* variables on top of the config file
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub overrides: Vec<Override>,
+}
+
+impl Resolution {
+	pub fn new(provenance: &Provenance) -> Self {
+		Self {
+			rinb_version: env!("CARGO_PKG_VERSION").to_string(),
+			resolved_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+			source: provenance.source.clone(),
+			catalog_sha256: provenance.catalog_sha256.clone(),
+			file: provenance.file.clone(),
+			build: None,
+			artifacts: BTreeMap::new(),
+			overrides: Vec::new(),
+		}
+	}
+}
+
+/// contents of a lock file, the resolved config with how it was resolved.
+///
+/// Older versions are upgraded when the lock is written again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lock {
+	/// missing in version 0
+	#[serde(default)]
+	pub lock_version: u32,
+	#[serde(flatten)]
+	pub config: Config,
+	pub resolution: Option<Resolution>,
+}
+
+impl Lock {
+	/// lock of resolved with overrides, keeping the resolution of previous if the pins didn't change
+	pub fn new(
+		resolved: Config,
+		overrides: &[Override],
+		provenance: &Provenance,
+		previous: Option<&Lock>,
+	) -> Result<Self, Error> {
+		let mut resolution = None;
+		if let Some(previous) = previous
+			&& changes(&previous.config, &resolved)?.is_empty()
+		{
+			resolution = previous.resolution.clone();
+		}
+		let mut resolution = resolution.unwrap_or_else(|| Resolution::new(provenance));
+		resolution.overrides = overrides.to_vec();
+		Ok(Self {
+			lock_version: LOCK_VERSION,
+			config: resolved,
+			resolution: Some(resolution),
+		})
+	}
+
+	/// records the sha256 of an artifact produced from this lock
+	pub fn record_artifact(&mut self, path: &Path) -> Result<(), Error> {
+		let sha256 = sha256_file(path)?;
+		if let Some(resolution) = &mut self.resolution {
+			resolution
+				.artifacts
+				.insert(path.to_string_lossy().to_string(), sha256);
+		}
+		Ok(())
+	}
+}
+
+/// lock file of a config with profiles
+#[derive(Debug, Serialize, Deserialize)]
+struct ProfileLocks {
+	lock_version: u32,
+	profiles: BTreeMap<String, Lock>,
+}
+
+/// every lock of the lock file at lock_path with its profile, None for a config without profiles
+pub fn read_locks(lock_path: &Path) -> Result<Vec<(Option<String>, Lock)>, Error> {
+	let data = fs::read_to_string(lock_path)
+		.map_err(|err| anyhow!("Failed to read lock file {lock_path:?}: {err}"))?;
+	let invalid = |err: &dyn std::fmt::Display| anyhow!("Invalid lock file {lock_path:?}: {err}");
+	let value: Value = serde_json5::from_str(&data).map_err(|err| invalid(&err))?;
+
+	let lock_version = value["lock_version"].as_u64().unwrap_or_default();
+	if lock_version > LOCK_VERSION as u64 {
+		return Err(anyhow!(
+			"Lock file {lock_path:?} has version {lock_version}, but this rinb only supports up to {LOCK_VERSION}. Update rinb"
+		));
+	}
+
+	if value.get("profiles").is_some() {
+		let locks: ProfileLocks = serde_json::from_value(value).map_err(|err| invalid(&err))?;
+		return Ok(locks
+			.profiles
+			.into_iter()
+			.map(|(profile, lock)| (Some(profile), lock))
+			.collect());
+	}
+	let lock: Lock = serde_json::from_value(value).map_err(|err| invalid(&err))?;
+	Ok(vec![(None, lock)])
+}
+
+/// lock of profile in the lock file at lock_path, None if there's none yet
+pub fn read_lock(lock_path: &Path, profile: Option<&str>) -> Result<Option<Lock>, Error> {
+	if !lock_path.exists() {
+		return Ok(None);
+	}
+	Ok(read_locks(lock_path)?
+		.into_iter()
+		.find(|(name, _)| name.as_deref() == profile)
+		.map(|(_, lock)| lock))
+}
+
+/// writes the lock of profile, keeping the locks of the other profiles
+pub fn write_lock(lock_path: &Path, profile: Option<&str>, lock: &Lock) -> Result<(), Error> {
+	let Some(profile) = profile else {
+		fs::write(lock_path, serde_json::to_string_pretty(lock)?)?;
+		return Ok(());
+	};
+
+	let mut profiles = BTreeMap::new();
+	if lock_path.exists() {
+		for (name, lock) in read_locks(lock_path)? {
+			if let Some(name) = name {
+				profiles.insert(name, lock);
+			}
+		}
+	}
+	profiles.insert(profile.to_string(), lock.clone());
+	let locks = ProfileLocks {
+		lock_version: LOCK_VERSION,
+		profiles,
+	};
+	fs::write(lock_path, serde_json::to_string_pretty(&locks)?)?;
+	Ok(())
+}
+
+pub fn sha256_file(path: &Path) -> Result<String, Error> {
+	let mut hasher = Sha256::new();
+	io::copy(&mut File::open(path)?, &mut hasher)?;
+	Ok(hex::encode(hasher.finalize()))
+}
+
+/// fields which differ between old and new, like `edition: "Professional" -> "Education"`
+fn diff(old: &Config, new: &Config, fields: &[&str]) -> Result<Vec<String>, Error> {
+	let (old, new) = (serde_json::to_value(old)?, serde_json::to_value(new)?);
+	Ok(fields
+		.iter()
+		.filter(|field| old[**field] != new[**field])
+		.map(|field| format!("{field}: {} -> {}", old[*field], new[*field]))
+		.collect())
+}
+
+/// changes of config since lock was resolved from it, empty if the lock still applies.
+///
+/// url, sha1size and pin only count if the config pins them itself
+pub fn drift(config: &Config, lock: &Config) -> Result<Vec<String>, Error> {
+	let mut fields = SELECTING.to_vec();
+	if config.url.is_some() {
+		fields.push("url");
+	}
+	if config.sha1size.is_some() {
+		fields.push("sha1size");
+	}
+	if config.pin.is_some() {
+		fields.push("pin");
+	}
+	diff(lock, config, &fields)
+}
+
+/// changes resolving made to the pins of the lock, empty if it doesn't need to be written
+pub fn changes(lock: &Config, resolved: &Config) -> Result<Vec<String>, Error> {
+	let mut fields = SELECTING.to_vec();
+	fields.extend(PINNED);
+	diff(lock, resolved, &fields)
+}
+
+/// config pinned to the artifact of lock, assumes there's no drift
+pub fn apply(config: &Config, lock: &Config) -> Config {
+	let mut config = config.clone();
+	config.url = lock.url.clone();
+	config.sha1size = lock.sha1size.clone();
+	config.pin = lock.pin.clone();
+	config.served_by = lock.served_by.clone();
+	config
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn config(fields: Value) -> Config {
+		let mut config = json!({
+			"version": "11",
+			"arch": "amd64",
+			"edition": "Professional",
+			"lang": "en-us",
+		});
+		crate::profile::merge(&mut config, fields);
+		serde_json::from_value(config).unwrap()
+	}
+
+	const SHA1SIZE: &str = "8ceab2838f8e90180ac7490e8752157fb05588cb:4680320173";
+
+	#[test]
+	fn no_drift_from_pins_written_by_resolving() {
+		let lock = config(json!({ "build": "26100.4349", "sha1size": SHA1SIZE }));
+		assert!(
+			drift(&config(json!({ "build": "26100.4349" })), &lock)
+				.unwrap()
+				.is_empty()
+		);
+	}
+
+	#[test]
+	fn drift_of_selecting_fields() {
+		let lock = config(json!({ "sha1size": SHA1SIZE }));
+		assert_eq!(
+			drift(&config(json!({ "edition": "Education" })), &lock).unwrap(),
+			[r#"edition: "Professional" -> "Education""#]
+		);
+		assert_eq!(
+			drift(&config(json!({ "build": "26100.4349" })), &lock).unwrap(),
+			["build: null -> \"26100.4349\""]
+		);
+	}
+
+	#[test]
+	fn drift_of_pins_set_by_the_config() {
+		let lock = config(json!({ "sha1size": SHA1SIZE }));
+		let pinned = config(json!({ "sha1size": "0123456789abcdef0123456789abcdef01234567:1" }));
+		assert_eq!(drift(&pinned, &lock).unwrap().len(), 1);
+		assert!(
+			drift(&config(json!({ "sha1size": SHA1SIZE })), &lock)
+				.unwrap()
+				.is_empty()
+		);
+	}
+
+	#[test]
+	fn changes_include_pins() {
+		let lock = config(json!({ "sha1size": SHA1SIZE }));
+		assert!(changes(&lock, &lock).unwrap().is_empty());
+		let resolved = config(json!({ "sha1size": SHA1SIZE, "pin": "sha256:ab:4680320173" }));
+		assert_eq!(
+			changes(&lock, &resolved).unwrap(),
+			[r#"pin: null -> "sha256:ab:4680320173""#]
+		);
+		// not selecting nor pinned
+		let mirrored = config(json!({ "sha1size": SHA1SIZE, "mirrors": ["http://mirror"] }));
+		assert!(changes(&lock, &mirrored).unwrap().is_empty());
+	}
+}
@@ -1,22 +1,38 @@
-use anyhow::{Error, Result};
+use anyhow::{Error, Result, anyhow};
 use std::{
-	fs,
+	env, fs,
 	path::{Path, PathBuf},
-	time::Instant,
+	process,
+	time::{Duration, Instant, SystemTime},
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
 use serde_json;
-use serde_json5;
 
 use mkwimg::{PackType, pack};
 
-use rinb::config::Config;
+use rinb::cache::{EsdCache, PruneOptions, parse_size, prune_candidates, referenced_sha1s};
 
-use rinb::esd_downloader::WinEsdDownloader;
+use rinb::config::{Arch, Config, HttpConfig, MajorWinVer};
+
+use rinb::esd_downloader::{FileInfo, WinEsdDownloader, filename_without_extension, parse_build};
 
 use rinb::esd::EsdFile;
 
+use rinb::iso::IsoFile;
+
+use rinb::lock::{Lock, apply, changes, drift, read_lock, write_lock};
+
+use rinb::profile::{Override, Profile, load_profiles};
+
+use rinb::remote_cache::RemoteCache;
+
+use rinb::serve::serve;
+
+use rinb::source::{Artifact, ArtifactKind, default_sources, pin, resolve};
+
 #[derive(ValueEnum, Debug, Clone)]
 #[clap(rename_all = "kebab_case")]
 enum OutType {
@@ -28,15 +44,190 @@ enum OutType {
 #[derive(Parser, Debug)]
 #[command(version, about = "Builds a customized windows installation")]
 struct Args {
-	/// Path to config file, {path}.lock{extension} will be used if it exists.
-	#[arg(long, default_value = "rinb.json", alias = "c")]
+	#[command(subcommand)]
+	command: Option<Command>,
+	/// Path to config file, pinned by {path}.lock{extension}
+	#[arg(long, default_value = "rinb.json", alias = "c", global = true)]
 	config: String,
-	#[arg(long, default_value = "out/devwin.iso", alias = "o")]
+	/// Output path, with -{profile} before the extension for profiles which don't set out
+	#[arg(long, default_value = "out/devwin.iso", alias = "o", global = true)]
 	out: String,
-	#[arg(long = "type", default_value = "iso", alias = "t")]
+	#[arg(long = "type", default_value = "iso", alias = "t", global = true)]
 	o_type: OutType,
-	#[arg(long, default_value = "./.rinbcache/esd_cache", alias = "cc")]
+	/// Fail if the lock file is missing, outdated or would change
+	#[arg(long, global = true)]
+	locked: bool,
+	/// Like --locked, and only use cached catalogs
+	#[arg(long, global = true)]
+	frozen: bool,
+	/// Profile of the config to use, all of them for lock and outdated if not specified
+	#[arg(long, global = true)]
+	profile: Option<String>,
+	/// Override a config field, e.g. edition=Education or http.retries=5. Applied after RINB_* variables like RINB_EDITION or RINB_HTTP__RETRIES
+	#[arg(long, value_name = "FIELD=VALUE", global = true)]
+	set: Vec<String>,
+	#[arg(
+		long,
+		default_value = "./.rinbcache/esd_cache",
+		alias = "cc",
+		global = true
+	)]
 	cache_path: String,
+	/// Number of parallel connections used for downloading the ESD
+	#[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=64), global = true)]
+	connections: u16,
+	/// Only use cached catalogs and ESDs, never access the network
+	#[arg(long, global = true)]
+	offline: bool,
+	/// Seconds after which cached catalogs are fetched again
+	#[arg(long, default_value_t = 24 * 60 * 60, global = true)]
+	catalog_ttl: u64,
+	/// Re-hash cached ESDs, even if they have been verified before
+	#[arg(long, global = true)]
+	paranoid: bool,
+	/// Mirror tried before those of the config, e.g. http://host:8080/{sha1}.esd for a `cache serve` mirror
+	#[arg(long, global = true)]
+	mirror: Vec<String>,
+	/// Remote cache for ESDs and build outputs, s3://{bucket}/{prefix} (configured by AWS_* variables) or http(s)://{url}
+	#[arg(long, global = true)]
+	remote_cache: Option<String>,
+	/// Upload ESDs and build outputs the remote cache doesn't have yet
+	#[arg(long, global = true, requires = "remote_cache")]
+	remote_cache_push: bool,
+	#[command(flatten)]
+	http: HttpArgs,
+}
+
+/// overrides config.http
+#[derive(clap::Args, Debug)]
+struct HttpArgs {
+	/// Proxy URL used for all requests, e.g. http://proxy:3128
+	#[arg(long, global = true)]
+	proxy: Option<String>,
+	/// Path to a PEM file with additional root certificates
+	#[arg(long, global = true)]
+	ca_bundle: Option<String>,
+	/// Connect timeout in seconds
+	#[arg(long, global = true)]
+	connect_timeout: Option<u64>,
+	/// Read timeout in seconds [default: 30]
+	#[arg(long, global = true)]
+	read_timeout: Option<u64>,
+	/// Retries for failed requests [default: 3]
+	#[arg(long, global = true)]
+	retries: Option<u32>,
+	/// Seconds to wait before the first retry, doubled for every further retry [default: 2]
+	#[arg(long, global = true)]
+	retry_backoff: Option<u64>,
+}
+
+impl HttpArgs {
+	fn over(&self, http: &HttpConfig) -> HttpConfig {
+		HttpConfig {
+			proxy: self.proxy.clone().or(http.proxy.clone()),
+			ca_bundle: self.ca_bundle.clone().or(http.ca_bundle.clone()),
+			connect_timeout: self.connect_timeout.or(http.connect_timeout),
+			read_timeout: self.read_timeout.or(http.read_timeout),
+			retries: self.retries.or(http.retries),
+			retry_backoff: self.retry_backoff.or(http.retry_backoff),
+		}
+	}
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Build the image, the default if no command is given
+	Build {
+		/// Build every profile of the config
+		#[arg(long, conflicts_with = "profile")]
+		all: bool,
+	},
+	/// List the ESD files available in the catalog
+	List(ListArgs),
+	/// Manage the ESD cache
+	#[command(subcommand)]
+	Cache(CacheCommand),
+	/// Resolve the config and write the lock file, without downloading or building
+	Lock {
+		/// Resolve again, ignoring the pins of the lock file
+		#[arg(long)]
+		update: bool,
+	},
+	/// Check the config against the catalog without downloading, suggesting valid values
+	Check,
+	/// Check whether the catalog lists a newer build than the lock file, exits with 2 if so
+	Outdated {
+		/// Update the lock file to the newer build
+		#[arg(long)]
+		update_lock: bool,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+	/// List cached ESD files with their size and last use
+	List,
+	/// Re-hash cached ESD files
+	Verify {
+		/// Remove files which fail verification
+		#[arg(long)]
+		remove_corrupted: bool,
+	},
+	/// Remove cached ESD files
+	Prune(PruneArgs),
+	/// Serve cached ESD files over HTTP as /{sha1}.esd, for use as a mirror
+	Serve {
+		/// Address to listen on
+		#[arg(long, default_value = "0.0.0.0:8080")]
+		bind: String,
+	},
+}
+
+#[derive(clap::Args, Debug)]
+struct PruneArgs {
+	/// Remove files not used for this many days
+	#[arg(long)]
+	older_than: Option<u64>,
+	/// Keep only this many most recently used files
+	#[arg(long)]
+	keep: Option<usize>,
+	/// Remove least recently used files until the cache fits, e.g. 20G
+	#[arg(long, value_parser = parse_size)]
+	max_size: Option<u64>,
+	/// Remove files not referenced by any of these lock files
+	#[arg(long, num_args = 1..)]
+	unreferenced: Vec<PathBuf>,
+	/// Only print what would be removed
+	#[arg(long)]
+	dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+	/// Major windows version, both if not specified
+	#[arg(long)]
+	version: Option<MajorWinVer>,
+	#[arg(long)]
+	lang: Option<String>,
+	#[arg(long)]
+	edition: Option<String>,
+	#[arg(long)]
+	arch: Option<Arch>,
+	/// Include files of older builds from the catalog history
+	#[arg(long)]
+	history: bool,
+	/// Print as json instead of a table
+	#[arg(long)]
+	json: bool,
+}
+
+#[derive(Serialize)]
+struct ListEntry<'a> {
+	version: &'a str,
+	#[serde(flatten)]
+	file: &'a FileInfo,
+	build: String,
+	sha1size: String,
 }
 
 impl Args {
@@ -59,59 +250,570 @@ impl Args {
 
 		parent.join(new_name)
 	}
+
+	/// profiles of the config to use, those selected by --profile or all of them with all
+	fn profiles(&self, all: bool) -> Result<Vec<Profile>, Error> {
+		let mut overrides = Override::from_env(env::vars());
+		for set in &self.set {
+			overrides.push(Override::parse_set(set)?);
+		}
+		let profiles = load_profiles(Path::new(&self.config), &overrides)?;
+		let names = || {
+			let names: Vec<&str> = profiles.iter().filter_map(|p| p.name.as_deref()).collect();
+			names.join(", ")
+		};
+		if let Some(name) = &self.profile {
+			return match profiles.iter().find(|p| p.name.as_ref() == Some(name)) {
+				Some(profile) => Ok(vec![profile.clone()]),
+				None if profiles[0].name.is_none() => Err(anyhow!(
+					"{} has no profiles, but --profile was passed",
+					self.config
+				)),
+				None => Err(anyhow!(
+					"{} has no profile {name}, it has {}",
+					self.config,
+					names()
+				)),
+			};
+		}
+		if !all && profiles[0].name.is_some() {
+			return Err(anyhow!(
+				"{} has profiles {}, select one with --profile or build all with `rinb build --all`",
+				self.config,
+				names()
+			));
+		}
+		Ok(profiles)
+	}
+
+	/// output path of profile, --out with -{name} before the extension if it doesn't set one
+	fn out_path(&self, profile: &Profile) -> PathBuf {
+		if let Some(out) = &profile.out {
+			return PathBuf::from(out);
+		}
+		let out = PathBuf::from(&self.out);
+		let Some(name) = &profile.name else {
+			return out;
+		};
+		let stem = out.file_stem().unwrap_or_default().to_string_lossy();
+		let file_name = match out.extension() {
+			Some(extension) => format!("{stem}-{name}.{}", extension.to_string_lossy()),
+			None => format!("{stem}-{name}"),
+		};
+		out.with_file_name(file_name)
+	}
+
+	/// downloader with http options from the command line over those of config
+	fn downloader(&self, config: Option<&Config>) -> Result<WinEsdDownloader, Error> {
+		let http = self
+			.http
+			.over(&config.map(|c| c.http.clone()).unwrap_or_default());
+		let mut downloader = WinEsdDownloader::new(&self.cache_path, &http)?;
+		downloader.connections = self.connections as usize;
+		downloader.offline = self.offline;
+		downloader.frozen = self.frozen;
+		downloader.catalog_ttl = Duration::from_secs(self.catalog_ttl);
+		downloader.paranoid = self.paranoid;
+		downloader.mirrors = self.mirror.clone();
+		Ok(downloader)
+	}
+
+	fn remote_cache(&self, config: &Config) -> Result<Option<RemoteCache>, Error> {
+		let Some(url) = &self.remote_cache else {
+			return Ok(None);
+		};
+		if self.offline {
+			return Ok(None);
+		}
+		let http = self.http.over(&config.http);
+		Ok(Some(RemoteCache::open(url, &http, self.remote_cache_push)?))
+	}
+
+	/// --locked or --frozen, if passed
+	fn lock_flag(&self) -> Option<&'static str> {
+		if self.frozen {
+			Some("--frozen")
+		} else if self.locked {
+			Some("--locked")
+		} else {
+			None
+		}
+	}
 }
 
 fn main() -> Result<(), Error> {
 	let args = Args::parse();
-	let mut config: Config;
+	match &args.command {
+		Some(Command::List(list_args)) => list(&args, list_args),
+		Some(Command::Cache(cache_command)) => cache(&args, cache_command),
+		Some(Command::Lock { update }) => lock(&args, *update),
+		Some(Command::Check) => check(&args),
+		Some(Command::Outdated { update_lock }) => outdated(&args, *update_lock),
+		Some(Command::Build { all }) => build(&args, *all),
+		None => build(&args, false),
+	}
+}
+
+fn cache(args: &Args, cache_command: &CacheCommand) -> Result<(), Error> {
+	let esd_cache = EsdCache::new(&args.cache_path);
+	esd_cache.migrate()?;
+	let entries = esd_cache.entries()?;
+
+	match cache_command {
+		CacheCommand::List => {
+			let now = SystemTime::now();
+			for entry in entries.iter().rev() {
+				let unused = now.duration_since(entry.last_used).unwrap_or_default();
+				println!(
+					"{:>11}  used {:>16} ago  {}  {}",
+					HumanBytes(entry.size).to_string(),
+					HumanDuration(unused).to_string(),
+					entry.name(),
+					entry.names.join(", ")
+				);
+			}
+			let total: u64 = entries.iter().map(|e| e.size).sum();
+			println!("{} files, {} total", entries.len(), HumanBytes(total));
+		}
+		CacheCommand::Verify { remove_corrupted } => {
+			let mut failed = 0;
+			for entry in &entries {
+				if let Err(err) = esd_cache.verify(entry) {
+					failed += 1;
+					eprintln!("{} is corrupted: {err}", entry.name());
+					if *remove_corrupted {
+						esd_cache.remove(entry)?;
+					}
+				}
+			}
+			if failed > 0 {
+				return Err(anyhow!(
+					"{failed} of {} cached files failed verification",
+					entries.len()
+				));
+			}
+			println!("Verified {} cached files", entries.len());
+		}
+		CacheCommand::Prune(prune_args) => {
+			let referenced = if prune_args.unreferenced.is_empty() {
+				None
+			} else {
+				Some(referenced_sha1s(&prune_args.unreferenced)?)
+			};
+			let options = PruneOptions {
+				older_than: prune_args
+					.older_than
+					.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+				keep: prune_args.keep,
+				max_size: prune_args.max_size,
+				referenced,
+			};
 
-	// identify cfg_path to use (lock or regular)
-	let cfg_path: PathBuf;
+			let remove = prune_candidates(entries, &options);
+			let freed: u64 = remove.iter().map(|e| e.size).sum();
+			for entry in &remove {
+				println!("Removing {}", entry.name());
+				if !prune_args.dry_run {
+					esd_cache.remove(entry)?;
+				}
+			}
+			println!("Freed {}", HumanBytes(freed));
+		}
+		CacheCommand::Serve { bind } => serve(&esd_cache.directory, bind)?,
+	}
+	Ok(())
+}
+
+fn list(args: &Args, list_args: &ListArgs) -> Result<(), Error> {
+	let downloader = args.downloader(None)?;
+	let versions = match &list_args.version {
+		Some(version) => vec![*version],
+		None => vec![MajorWinVer::Win10, MajorWinVer::Win11],
+	};
+
+	let matches = |file: &FileInfo| {
+		file.matches(
+			list_args.lang.as_deref(),
+			list_args.edition.as_deref(),
+			list_args.arch.as_ref(),
+		)
+	};
+	let mut files = Vec::new();
+	for version in &versions {
+		for file in downloader.files(version)? {
+			if matches(&file) {
+				files.push((*version, file));
+			}
+		}
+	}
+	if list_args.history {
+		// older builds the catalog doesn't list anymore
+		let history = downloader.history()?;
+		for entry in history.entries.into_iter().rev() {
+			let listed = files.iter().any(|(version, file)| {
+				*version == entry.version
+					&& file.sha1 == entry.file.sha1
+					&& file.language_code == entry.file.language_code
+					&& file.edition == entry.file.edition
+					&& file.architecture == entry.file.architecture
+			});
+			if versions.contains(&entry.version) && matches(&entry.file) && !listed {
+				files.push((entry.version, entry.file));
+			}
+		}
+	}
+
+	let entries: Vec<ListEntry> = files
+		.iter()
+		.map(|(version, file)| ListEntry {
+			version: version.as_str(),
+			file,
+			build: file.build(),
+			sha1size: format!("{}:{}", file.sha1, file.size),
+		})
+		.collect();
+
+	if list_args.json {
+		println!("{}", serde_json::to_string_pretty(&entries)?);
+		return Ok(());
+	}
+
+	let header = [
+		"VERSION", "BUILD", "LANG", "EDITION", "ARCH", "SIZE", "SHA1SIZE", "URL",
+	];
+	let rows: Vec<[String; 8]> = entries
+		.iter()
+		.map(|e| {
+			[
+				e.version.to_string(),
+				e.build.clone(),
+				e.file.language_code.clone(),
+				e.file.edition.clone(),
+				e.file.architecture.clone(),
+				e.file.size.to_string(),
+				e.sha1size.clone(),
+				e.file.file_path.clone(),
+			]
+		})
+		.collect();
+
+	let mut widths = header.map(|h| h.len());
+	for row in &rows {
+		for (width, cell) in widths.iter_mut().zip(row) {
+			*width = (*width).max(cell.len());
+		}
+	}
+	let header = header.map(|h| h.to_string());
+	for row in [&header].into_iter().chain(&rows) {
+		let line: Vec<String> = row
+			.iter()
+			.zip(widths)
+			.map(|(cell, width)| format!("{cell:<width$}"))
+			.collect();
+		println!("{}", line.join("  ").trim_end());
+	}
+	Ok(())
+}
+
+/// checks that the catalog lists an ESD for every selected profile, ignoring the lock file
+fn check(args: &Args) -> Result<(), Error> {
+	let profiles = args.profiles(true)?;
+	let mut failed = 0;
+	for profile in &profiles {
+		let label = profile.label(&args.config);
+		let config = &profile.config;
+		if config.iso.is_some() || config.url.is_some() {
+			println!("{label}: uses a custom url or iso, not listed in the catalog");
+			continue;
+		}
+		let downloader = args.downloader(Some(config))?;
+		match downloader.pin_file(config) {
+			Ok((file, _)) => println!("{label}: {} {}", file.build(), file.file_name),
+			Err(err) => {
+				failed += 1;
+				eprintln!("{label}: {err}");
+			}
+		}
+	}
+	if failed > 0 {
+		return Err(anyhow!(
+			"{failed} of {} configs are invalid",
+			profiles.len()
+		));
+	}
+	Ok(())
+}
+
+/// exit code of outdated if a newer build is available
+const EXIT_OUTDATED: i32 = 2;
+
+fn outdated(args: &Args, update_lock: bool) -> Result<(), Error> {
+	let mut outdated = false;
+	for profile in args.profiles(true)? {
+		if let Some(name) = &profile.name {
+			println!("{name}:");
+		}
+		outdated |= outdated_profile(args, &profile, update_lock)?;
+	}
+	if outdated {
+		process::exit(EXIT_OUTDATED);
+	}
+	Ok(())
+}
+
+/// whether the catalog lists a newer build than the lock of profile
+fn outdated_profile(args: &Args, profile: &Profile, update_lock: bool) -> Result<bool, Error> {
 	let lock_path = &args.lock_path();
-	if lock_path.exists() {
-		cfg_path = lock_path.to_path_buf()
-	} else {
-		cfg_path = PathBuf::from(&args.config)
+	let Some(lock) = read_lock(lock_path, profile.name.as_deref())? else {
+		return Err(anyhow!(
+			"No lock for {} at {lock_path:?}, build once to create it",
+			profile.label(&args.config)
+		));
+	};
+	let config = &profile.config;
+	if lock.config.iso.is_some() || config.url.is_some() {
+		println!("Locked to a custom url or iso, not listed in the catalog");
+		return Ok(false);
 	}
+
+	let (locked_sha1, locked_size) = lock.config.parse_sha1size()?;
+	let locked_build = match &lock.config.url {
+		Some(url) => parse_build(&filename_without_extension(url)?),
+		None => String::new(),
+	};
+
+	// latest file for the config, regardless of what's pinned
+	let mut latest_config = lock.config.clone();
+	latest_config.url = None;
+	latest_config.build = None;
+	latest_config.sha1size = None;
+	let downloader = args.downloader(Some(&lock.config))?;
+	let provenance = pin(&default_sources(&downloader, None), &latest_config)?;
+	let latest = provenance
+		.file
+		.clone()
+		.ok_or_else(|| anyhow!("No catalog entry for the latest build"))?;
+
+	let build_number = |build: &str| -> Vec<u64> {
+		build
+			.split('.')
+			.filter_map(|part| part.parse().ok())
+			.collect()
+	};
+	println!(
+		"Locked: {locked_build:<12} {:>11}  {locked_sha1}:{locked_size}",
+		HumanBytes(locked_size).to_string()
+	);
+	println!(
+		"Latest: {:<12} {:>11}  {}:{}",
+		latest.build(),
+		HumanBytes(latest.size).to_string(),
+		latest.sha1,
+		latest.size
+	);
+
+	if latest.sha1.eq_ignore_ascii_case(&locked_sha1)
+		|| build_number(&latest.build()) < build_number(&locked_build)
 	{
-		let data = fs::read_to_string(cfg_path)?;
-		config = serde_json5::from_str(&data)?;
+		println!("Up to date");
+		return Ok(false);
 	}
 
-	// download esd image
-	let (esd, sha1size, url): (PathBuf, String, String);
-	{
-		let downloader = WinEsdDownloader::new(args.cache_path)?;
-		(esd, sha1size, url) = downloader.download(&config)?;
+	let delta = if latest.size >= locked_size {
+		format!("+{}", HumanBytes(latest.size - locked_size))
+	} else {
+		format!("-{}", HumanBytes(locked_size - latest.size))
+	};
+	println!(
+		"Update available: {locked_build} -> {} ({delta})",
+		latest.build()
+	);
+
+	if let Some(build) = &config.build {
+		println!(
+			"{} pins build {build}, change it to update",
+			profile.label(&args.config)
+		);
+	} else if update_lock {
+		let mut updated = lock.config.clone();
+		provenance.lock(&mut updated);
+		let overrides = match &lock.resolution {
+			Some(resolution) => resolution.overrides.clone(),
+			None => Vec::new(),
+		};
+		let updated = Lock::new(updated, &overrides, &provenance, Some(&lock))?;
+		write_lock(lock_path, profile.name.as_deref(), &updated)?;
+		println!("Updated {}", profile.label(&format!("{lock_path:?}")));
 	}
+	Ok(true)
+}
 
-	// lock esd for url & sha1size
-	config.url = Some(url);
-	config.sha1size = Some(sha1size);
+/// config of profile pinned by the lock file if it still applies, and its lock.
+///
+/// With update, the pins of the lock file are ignored
+fn locked_config(
+	args: &Args,
+	profile: &Profile,
+	update: bool,
+) -> Result<(Config, Option<Lock>), Error> {
+	let config = profile.config.clone();
+	let label = profile.label(&args.config);
+	let lock_path = &args.lock_path();
+	let Some(lock) = read_lock(lock_path, profile.name.as_deref())? else {
+		if let Some(flag) = args.lock_flag() {
+			return Err(anyhow!(
+				"No lock for {label} at {lock_path:?}, but {flag} was passed. Run `rinb lock` to create it"
+			));
+		}
+		return Ok((config, None));
+	};
+
+	if update {
+		return Ok((config, Some(lock)));
+	}
+	let drift = drift(&config, &lock.config)?;
+	if drift.is_empty() {
+		return Ok((apply(&config, &lock.config), Some(lock)));
+	}
+	let drift = drift.join("\n  ");
+	if let Some(flag) = args.lock_flag() {
+		return Err(anyhow!(
+			"{label} changed since {lock_path:?} was written, but {flag} was passed. Run `rinb lock` to update it:\n  {drift}"
+		));
+	}
+	println!("{label} changed since {lock_path:?} was written, resolving again:\n  {drift}");
+	Ok((config, Some(lock)))
+}
+
+/// writes resolved to the lock of profile if it changed, returns whether it was written
+fn update_lock(
+	args: &Args,
+	profile: &Profile,
+	lock: Option<&Lock>,
+	resolved: &Lock,
+) -> Result<bool, Error> {
+	let lock_path = &args.lock_path();
+	let label = profile.label(&format!("{lock_path:?}"));
+	let changes = match lock {
+		Some(lock) => changes(&lock.config, &resolved.config)?,
+		None => Vec::new(),
+	};
+	if let Some(flag) = args.lock_flag() {
+		if !changes.is_empty() {
+			return Err(anyhow!(
+				"{label} needs to be updated, but {flag} was passed:\n  {}",
+				changes.join("\n  ")
+			));
+		}
+		return Ok(false);
+	}
+
+	let unchanged = match lock {
+		Some(lock) => serde_json::to_value(lock)? == serde_json::to_value(resolved)?,
+		None => false,
+	};
+	if unchanged {
+		return Ok(false);
+	}
+	write_lock(lock_path, profile.name.as_deref(), resolved)?;
+	if changes.is_empty() {
+		println!("Wrote {label}");
+	} else {
+		println!("Updated {label}:\n  {}", changes.join("\n  "));
+	}
+	Ok(true)
+}
+
+fn lock(args: &Args, update: bool) -> Result<(), Error> {
+	for profile in args.profiles(true)? {
+		let (mut config, lock) = locked_config(args, &profile, update)?;
+		let downloader = args.downloader(Some(&config))?;
+		let provenance = pin(&default_sources(&downloader, None), &config)?;
+		provenance.lock(&mut config);
+		let resolved = Lock::new(config, &profile.overrides, &provenance, lock.as_ref())?;
+		if !update_lock(args, &profile, lock.as_ref(), &resolved)? {
+			let lock_path = args.lock_path();
+			println!("{} is up to date", profile.label(&format!("{lock_path:?}")));
+		}
+	}
+	Ok(())
+}
+
+/// builds the selected profiles one after another, they share the ESD cache
+fn build(args: &Args, all: bool) -> Result<(), Error> {
+	for profile in args.profiles(all)? {
+		if let Some(name) = &profile.name {
+			println!("Building profile {name}");
+		}
+		build_profile(args, &profile)?;
+	}
+	Ok(())
+}
+
+fn build_profile(args: &Args, profile: &Profile) -> Result<(), Error> {
+	for o in &profile.overrides {
+		println!("Overriding {} with {} from {}", o.field, o.value, o.from);
+	}
+	let (mut config, lock) = locked_config(args, profile, false)?;
+
+	// download esd image, or use the iso
+	let artifact: Artifact;
+	let remote_cache = args.remote_cache(&config)?;
 	{
-		let data = serde_json::to_string_pretty(&config)?;
-		fs::write(lock_path, data)?
+		let downloader = args.downloader(Some(&config))?;
+		let sources = default_sources(&downloader, remote_cache.as_ref());
+		artifact = resolve(&sources, &config)?;
 	}
 
+	// lock for sha1size (and url of the esd)
+	artifact.lock(&mut config);
+	let mut resolved = Lock::new(
+		config.clone(),
+		&profile.overrides,
+		&artifact.provenance,
+		lock.as_ref(),
+	)?;
+	update_lock(args, profile, lock.as_ref(), &resolved)?;
+
+	let out = args.out_path(profile);
 	//let tmp_dir = &TmpDir::new()?;
 	//let tmp_dir_path = &tmp_dir.path;
-	let tmp_dir_path = PathBuf::from(&args.out).parent().unwrap().join("isodir"); // for debugging
+	let tmp_dir_path = out.parent().unwrap().join("isodir"); // for debugging
 
 	println!("Starting to build");
 	let now = Instant::now();
 
-	// create install dir from esd
-	let esdf = EsdFile::new(&esd)?;
-	// println!("{}", esdf.xml()?);
 	fs::remove_dir_all(&tmp_dir_path)?; // we don't want left-over extra files
-	esdf.install_dir(&tmp_dir_path, &config.edition, &(u32::MAX as u64))?;
+	if artifact.kind == ArtifactKind::Iso {
+		// create install dir from iso
+		let isof = IsoFile::new(&artifact.path)?;
+		isof.install_dir(&tmp_dir_path, &config.edition, &(u32::MAX as u64))?;
+	} else {
+		// create install dir from esd
+		let mut esdf = EsdFile::new(&artifact.path)?;
+		if let Some(remote_cache) = &remote_cache {
+			let (sha1, _) = config.parse_sha1size()?;
+			esdf.remote_cache = Some((remote_cache, sha1));
+		}
+		// println!("{}", esdf.xml()?);
+		if let Some(resolution) = &mut resolved.resolution {
+			resolution.build = esdf.build(&config.edition)?;
+		}
+		esdf.install_dir(&tmp_dir_path, &config.edition, &(u32::MAX as u64))?;
+	}
 
-	let outp = Path::new(&args.out);
+	let outp = out.as_path();
 	match args.o_type {
 		OutType::ISO => pack(&tmp_dir_path, outp, PackType::ISO)?,
 		OutType::VHD => pack(&tmp_dir_path, outp, PackType::VHD)?,
 		OutType::IMG => pack(&tmp_dir_path, outp, PackType::IMG)?,
 	}
 	println!("Building took {:.2?}", now.elapsed());
-	 Ok(())
+
+	// the lock mustn't change with --locked
+	if args.lock_flag().is_none() {
+		resolved.record_artifact(outp)?;
+		write_lock(&args.lock_path(), profile.name.as_deref(), &resolved)?;
+	}
+	Ok(())
 }
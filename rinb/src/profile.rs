@@ -0,0 +1,504 @@
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Error, Result, anyhow};
+use schemars::schema_for;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::Config;
+
+/// config to build, a profile of the config file or the whole file if it has none
+#[derive(Debug, Clone)]
+pub struct Profile {
+	/// None for a config without profiles
+	pub name: Option<String>,
+	pub config: Config,
+	/// output path set by the profile
+	pub out: Option<String>,
+	/// overrides applied to config
+	pub overrides: Vec<Override>,
+}
+
+impl Profile {
+	/// path of the config or lock file with the name of the profile, for messages
+	pub fn label(&self, path: &str) -> String {
+		match &self.name {
+			Some(name) => format!("{path} (profile {name})"),
+			None => path.to_string(),
+		}
+	}
+}
+
+/// config field set by --set or a RINB_* environment variable
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Override {
+	/// dotted path of the field, e.g. http.retries
+	pub field: String,
+	pub value: String,
+	/// --set or the name of the variable
+	pub from: String,
+}
+
+impl Override {
+	/// RINB_* environment variables of vars naming a config field, RINB_HTTP__RETRIES sets http.retries.
+	///
+	/// Others like RINB_OFFLINE for build.rs are ignored
+	pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Self> {
+		let mut overrides: Vec<Self> = vars
+			.into_iter()
+			.filter_map(|(name, value)| {
+				let field = name
+					.strip_prefix("RINB_")?
+					.to_lowercase()
+					.replace("__", ".");
+				if !is_config_field(&field) {
+					return None;
+				}
+				Some(Self {
+					field,
+					value,
+					from: name,
+				})
+			})
+			.collect();
+		overrides.sort_by(|a, b| a.from.cmp(&b.from));
+		overrides
+	}
+
+	/// override of --set field=value
+	pub fn parse_set(set: &str) -> Result<Self, Error> {
+		let (field, value) = set
+			.split_once('=')
+			.ok_or_else(|| anyhow!("--set expects field=value, got {set}"))?;
+		Ok(Self {
+			field: field.trim().to_string(),
+			value: value.to_string(),
+			from: "--set".to_string(),
+		})
+	}
+
+	/// sets the field in the config value.
+	///
+	/// The value is used as a string if the field takes one, parsed as JSON5 otherwise
+	fn apply(&self, config: &mut Value) -> Result<(), Error> {
+		let string = Value::String(self.value.clone());
+		let candidates = match serde_json5::from_str::<Value>(&self.value) {
+			// e.g. version or build look like numbers
+			Ok(parsed @ (Value::Number(_) | Value::Bool(_))) => vec![string, parsed],
+			Ok(parsed) => vec![parsed],
+			Err(_) => vec![string],
+		};
+		for candidate in &candidates {
+			self.set(config, candidate.clone())?;
+			if serde_json::from_value::<Config>(config.clone()).is_ok() {
+				return Ok(());
+			}
+		}
+		// invalid either way, reported when the config is read
+		self.set(config, candidates[0].clone())
+	}
+
+	fn set(&self, config: &mut Value, value: Value) -> Result<(), Error> {
+		let not_object = || {
+			anyhow!(
+				"{} sets {}, which isn't in an object",
+				self.from,
+				self.field
+			)
+		};
+		let (parents, key) = match self.field.rsplit_once('.') {
+			Some((parents, key)) => (parents.split('.').collect(), key),
+			None => (Vec::new(), self.field.as_str()),
+		};
+		let mut target = config;
+		for parent in parents {
+			target = target
+				.as_object_mut()
+				.ok_or_else(not_object)?
+				.entry(parent)
+				.or_insert_with(|| Value::Object(Map::new()));
+		}
+		target
+			.as_object_mut()
+			.ok_or_else(not_object)?
+			.insert(key.to_string(), value);
+		Ok(())
+	}
+}
+
+/// whether field is the dotted path of a field of the config schema, e.g. http.retries
+fn is_config_field(field: &str) -> bool {
+	let schema = schema_for!(Config);
+	let root = schema.as_value();
+	let mut node = root;
+	for key in field.split('.') {
+		// e.g. #/$defs/HttpConfig
+		if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+			match root.pointer(reference.trim_start_matches('#')) {
+				Some(definition) => node = definition,
+				None => return false,
+			}
+		}
+		match node
+			.get("properties")
+			.and_then(|properties| properties.get(key))
+		{
+			Some(property) => node = property,
+			None => return false,
+		}
+	}
+	true
+}
+
+/// merges overlay into base, objects recursively, anything else is replaced
+pub fn merge(base: &mut Value, overlay: Value) {
+	match (base, overlay) {
+		(Value::Object(base), Value::Object(overlay)) => {
+			for (key, value) in overlay {
+				match base.get_mut(&key) {
+					Some(existing) => merge(existing, value),
+					None => {
+						base.insert(key, value);
+					}
+				}
+			}
+		}
+		(base, overlay) => *base = overlay,
+	}
+}
+
+/// profile for every combination of the values of matrix, by name
+fn expand(matrix: BTreeMap<String, Vec<Value>>) -> BTreeMap<String, Value> {
+	if matrix.is_empty() {
+		return BTreeMap::new();
+	}
+	let mut combinations: Vec<(Vec<String>, Map<String, Value>)> = vec![(Vec::new(), Map::new())];
+	for (field, values) in matrix {
+		let mut expanded = Vec::new();
+		for (names, profile) in &combinations {
+			for value in &values {
+				let mut names = names.clone();
+				names.push(match value {
+					Value::String(value) => value.clone(),
+					value => value.to_string(),
+				});
+				let mut profile = profile.clone();
+				profile.insert(field.clone(), value.clone());
+				expanded.push((names, profile));
+			}
+		}
+		combinations = expanded;
+	}
+	combinations
+		.into_iter()
+		.map(|(names, profile)| (names.join("-"), Value::Object(profile)))
+		.collect()
+}
+
+/// config file at path merged over the one it extends, chain holds the files extending it
+fn load_value(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Value, Error> {
+	let canonical = path
+		.canonicalize()
+		.map_err(|err| anyhow!("Failed to read config {path:?}: {err}"))?;
+	if chain.contains(&canonical) {
+		return Err(anyhow!("Config {path:?} extends itself"));
+	}
+	chain.push(canonical);
+
+	let data =
+		fs::read_to_string(path).map_err(|err| anyhow!("Failed to read config {path:?}: {err}"))?;
+	let mut value: Value = serde_json5::from_str(&data)?;
+	let Some(object) = value.as_object_mut() else {
+		return Err(anyhow!("Config {path:?} must be an object"));
+	};
+	let extends = match object.remove("extends") {
+		None | Some(Value::Null) => return Ok(value),
+		Some(Value::String(extends)) => extends,
+		Some(extends) => {
+			return Err(anyhow!("extends in {path:?} must be a path, got {extends}"));
+		}
+	};
+
+	// relative to the including file
+	let base_path = path.parent().unwrap_or(Path::new("")).join(extends);
+	let mut base = load_value(&base_path, chain)?;
+	merge(&mut base, value);
+	Ok(base)
+}
+
+/// profile of the merged config value, with overrides applied
+fn profile(
+	path: &Path,
+	name: Option<String>,
+	mut value: Value,
+	overrides: &[Override],
+) -> Result<Profile, Error> {
+	for o in overrides {
+		o.apply(&mut value)?;
+	}
+	let out = match value.as_object_mut().and_then(|o| o.remove("out")) {
+		None | Some(Value::Null) => None,
+		Some(Value::String(out)) => Some(out),
+		Some(out) => return Err(anyhow!("out in {path:?} must be a string, got {out}")),
+	};
+	let config: Config = serde_json::from_value(value).map_err(|err| match &name {
+		Some(name) => anyhow!("Invalid profile {name} in {path:?}: {err}"),
+		None => anyhow!("Invalid config {path:?}: {err}"),
+	})?;
+
+	// config ignores unknown fields, overrides mustn't
+	let fields = serde_json::to_value(&config)?;
+	for o in overrides {
+		let pointer = format!("/{}", o.field.replace('.', "/"));
+		if o.field != "out" && fields.pointer(&pointer).is_none() {
+			return Err(anyhow!(
+				"{} sets {}, which isn't a config field",
+				o.from,
+				o.field
+			));
+		}
+	}
+	Ok(Profile {
+		name,
+		config,
+		out,
+		overrides: overrides.to_vec(),
+	})
+}
+
+/// profiles of the config file at path with overrides applied, a single unnamed one if it has none
+pub fn load_profiles(path: &Path, overrides: &[Override]) -> Result<Vec<Profile>, Error> {
+	let mut base = load_value(path, &mut Vec::new())?;
+	let object = base.as_object_mut().unwrap(); // checked by load_value
+
+	let profiles: BTreeMap<String, Value> = match object.remove("profiles") {
+		None | Some(Value::Null) => BTreeMap::new(),
+		Some(profiles) => serde_json::from_value(profiles)
+			.map_err(|err| anyhow!("Invalid profiles in {path:?}: {err}"))?,
+	};
+	let matrix: BTreeMap<String, Vec<Value>> = match object.remove("matrix") {
+		None | Some(Value::Null) => BTreeMap::new(),
+		Some(matrix) => serde_json::from_value(matrix)
+			.map_err(|err| anyhow!("Invalid matrix in {path:?}: {err}"))?,
+	};
+
+	// explicit profiles refine the matrix entries of the same name
+	let mut named = expand(matrix);
+	for (name, profile) in profiles {
+		match named.get_mut(&name) {
+			Some(existing) => merge(existing, profile),
+			None => {
+				named.insert(name, profile);
+			}
+		}
+	}
+
+	if named.is_empty() {
+		return Ok(vec![profile(path, None, base, overrides)?]);
+	}
+
+	named
+		.into_iter()
+		.map(|(name, overlay)| {
+			let mut value = base.clone();
+			merge(&mut value, overlay);
+			profile(path, Some(name), value, overrides)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::TmpDir;
+	use serde_json::json;
+
+	#[test]
+	fn merge_objects_recursively() {
+		let mut base = json!({
+			"edition": "Professional",
+			"http": { "retries": 3, "proxy": "http://proxy" },
+			"mirrors": ["a", "b"],
+		});
+		merge(
+			&mut base,
+			json!({
+				"edition": "Education",
+				"http": { "retries": 5 },
+				"mirrors": ["c"],
+				"lang": "de-de",
+			}),
+		);
+		assert_eq!(
+			base,
+			json!({
+				"edition": "Education",
+				"http": { "retries": 5, "proxy": "http://proxy" },
+				"mirrors": ["c"],
+				"lang": "de-de",
+			})
+		);
+	}
+
+	#[test]
+	fn expand_empty_matrix() {
+		assert!(expand(BTreeMap::new()).is_empty());
+		// a field without values has no combinations
+		let matrix = BTreeMap::from([("arch".to_string(), Vec::new())]);
+		assert!(expand(matrix).is_empty());
+	}
+
+	#[test]
+	fn expand_every_combination() {
+		let matrix = BTreeMap::from([
+			("arch".to_string(), vec![json!("amd64"), json!("arm64")]),
+			("version".to_string(), vec![json!(10), json!("11")]),
+		]);
+		let profiles = expand(matrix);
+		assert_eq!(
+			profiles.keys().collect::<Vec<_>>(),
+			["amd64-10", "amd64-11", "arm64-10", "arm64-11"]
+		);
+		assert_eq!(
+			profiles["arm64-10"],
+			json!({ "arch": "arm64", "version": 10 })
+		);
+	}
+
+	#[test]
+	fn extends_relative_to_including_file() {
+		let tmp = TmpDir::new().unwrap();
+		fs::create_dir(tmp.path.join("base")).unwrap();
+		fs::write(
+			tmp.path.join("base/rinb.json"),
+			r#"{ version: "11", arch: "amd64", edition: "Professional", lang: "en-us", http: { retries: 3 } }"#,
+		)
+		.unwrap();
+		let path = tmp.path.join("rinb.json");
+		fs::write(
+			&path,
+			r#"{ extends: "base/rinb.json", edition: "Education", http: { retries: 5 } }"#,
+		)
+		.unwrap();
+
+		let profiles = load_profiles(&path, &[]).unwrap();
+		assert_eq!(profiles.len(), 1);
+		let config = &profiles[0].config;
+		assert_eq!(config.edition, "Education");
+		assert_eq!(config.lang, "en-us");
+		assert_eq!(config.http.retries, Some(5));
+		assert_eq!(config.extends, None);
+	}
+
+	#[test]
+	fn extends_cycle() {
+		let tmp = TmpDir::new().unwrap();
+		let (a, b) = (tmp.path.join("a.json"), tmp.path.join("b.json"));
+		fs::write(&a, r#"{ extends: "b.json" }"#).unwrap();
+		fs::write(&b, r#"{ extends: "./a.json" }"#).unwrap();
+		let err = load_profiles(&a, &[]).unwrap_err();
+		assert!(err.to_string().contains("extends itself"), "{err}");
+
+		fs::write(&a, r#"{ extends: "a.json" }"#).unwrap();
+		assert!(load_profiles(&a, &[]).is_err());
+	}
+
+	fn set(set: &str) -> Override {
+		Override::parse_set(set).unwrap()
+	}
+
+	#[test]
+	fn parse_set() {
+		assert_eq!(
+			set(" http.retries =5"),
+			Override {
+				field: "http.retries".to_string(),
+				value: "5".to_string(),
+				from: "--set".to_string(),
+			}
+		);
+		// only split at the first =
+		assert_eq!(set("url=http://host/a?b=c").value, "http://host/a?b=c");
+		assert_eq!(set("build=").value, "");
+		assert!(Override::parse_set("edition").is_err());
+	}
+
+	#[test]
+	fn apply_strings_numbers_and_nested_fields() {
+		let mut config =
+			json!({ "version": "11", "arch": "amd64", "edition": "Pro", "lang": "en-us" });
+		set("edition=Education").apply(&mut config).unwrap();
+		// a string field, even though 10 parses as a number
+		set("version=10").apply(&mut config).unwrap();
+		set("http.retries=5").apply(&mut config).unwrap();
+		set("mirrors=['http://mirror']").apply(&mut config).unwrap();
+		assert_eq!(
+			config,
+			json!({
+				"version": "10",
+				"arch": "amd64",
+				"edition": "Education",
+				"lang": "en-us",
+				"http": { "retries": 5 },
+				"mirrors": ["http://mirror"],
+			})
+		);
+	}
+
+	#[test]
+	fn apply_below_a_value() {
+		let mut config = json!({ "edition": "Professional" });
+		assert!(set("edition.x=1").apply(&mut config).is_err());
+	}
+
+	#[test]
+	fn unknown_override_field() {
+		let value = json!({ "version": "11", "arch": "amd64", "edition": "Pro", "lang": "en-us" });
+		let err = profile(
+			Path::new("rinb.json"),
+			None,
+			value.clone(),
+			&[set("editon=Home")],
+		)
+		.unwrap_err();
+		assert!(err.to_string().contains("isn't a config field"), "{err}");
+		let profile = profile(Path::new("rinb.json"), None, value, &[set("out=x.iso")]).unwrap();
+		assert_eq!(profile.out.as_deref(), Some("x.iso"));
+	}
+
+	#[test]
+	fn env_overrides_only_name_config_fields() {
+		let vars = [
+			("RINB_OFFLINE", "1"),
+			("RINB_HTTP__RETRIES", "5"),
+			("RINB_EDITION", "Education"),
+			("PATH", "/usr/bin"),
+		];
+		let overrides =
+			Override::from_env(vars.map(|(name, value)| (name.to_string(), value.to_string())));
+		let fields: Vec<_> = overrides
+			.iter()
+			.map(|o| (o.from.as_str(), o.field.as_str(), o.value.as_str()))
+			.collect();
+		assert_eq!(
+			fields,
+			[
+				("RINB_EDITION", "edition", "Education"),
+				("RINB_HTTP__RETRIES", "http.retries", "5"),
+			]
+		);
+	}
+
+	#[test]
+	fn config_fields() {
+		assert!(is_config_field("edition"));
+		assert!(is_config_field("http.read_timeout"));
+		assert!(!is_config_field("offline"));
+		assert!(!is_config_field("http.offline"));
+		assert!(!is_config_field("edition.x"));
+	}
+}
@@ -0,0 +1,241 @@
+use std::{
+	fs::File,
+	io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+	net::{TcpListener, TcpStream},
+	path::Path,
+	thread,
+	time::Duration,
+};
+
+use anyhow::{Error, Result};
+
+use crate::esd_downloader::esd_path;
+use crate::utils::mark_used;
+
+/// connections handled at once, further ones wait until one is closed
+const MAX_CONNECTIONS: usize = 16;
+/// clients which don't send or receive anything for this long are disconnected
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// serves the ESDs of a cache directory as /{sha1}.esd over HTTP, with support for range requests.
+///
+/// Clients can use it as a mirror with "http://{host}:{port}/{sha1}.esd"
+pub fn serve(cache_directory: &Path, bind: &str) -> Result<(), Error> {
+	let listener = TcpListener::bind(bind)?;
+	println!(
+		"Serving {cache_directory:?} on http://{}/{{sha1}}.esd",
+		listener.local_addr()?
+	);
+
+	// a fixed number of threads accepting connections bounds the concurrency
+	thread::scope(|s| {
+		for _ in 0..MAX_CONNECTIONS {
+			s.spawn(|| {
+				loop {
+					let stream = match listener.accept() {
+						Ok((stream, _)) => stream,
+						Err(err) => {
+							eprintln!("Failed to accept connection: {err}");
+							continue;
+						}
+					};
+					let peer = stream
+						.peer_addr()
+						.map(|addr| addr.to_string())
+						.unwrap_or_default();
+					if let Err(err) = handle(cache_directory, stream) {
+						eprintln!("{peer}: {err}");
+					}
+				}
+			});
+		}
+	});
+	Ok(())
+}
+
+fn handle(cache_directory: &Path, mut stream: TcpStream) -> Result<(), Error> {
+	let peer = stream.peer_addr()?;
+	stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+	stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+	let mut reader = BufReader::new(stream.try_clone()?);
+
+	let mut request_line = String::new();
+	reader.read_line(&mut request_line)?;
+	let mut parts = request_line.split_whitespace();
+	let method = parts.next().unwrap_or_default().to_string();
+	let target = parts.next().unwrap_or_default().to_string();
+
+	let mut range = None;
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+			break;
+		}
+		if let Some((name, value)) = line.split_once(':')
+			&& name.trim().eq_ignore_ascii_case("range")
+		{
+			range = Some(value.trim().to_string());
+		}
+	}
+
+	let status = respond(
+		cache_directory,
+		&mut stream,
+		&method,
+		&target,
+		range.as_deref(),
+	)?;
+	println!("{peer} {method} {target} {status}");
+	Ok(())
+}
+
+/// writes the response, returns the status code
+fn respond(
+	cache_directory: &Path,
+	stream: &mut TcpStream,
+	method: &str,
+	target: &str,
+	range: Option<&str>,
+) -> Result<u16, Error> {
+	if method != "GET" && method != "HEAD" {
+		write_head(stream, 405, "Method Not Allowed", 0, &[])?;
+		return Ok(405);
+	}
+
+	// /{sha1}.esd or /{sha1}, nothing else to keep out of other files
+	let path = target.split(['?', '#']).next().unwrap_or_default();
+	let sha1 = path.trim_start_matches('/').trim_end_matches(".esd");
+	let file_path = esd_path(cache_directory, sha1);
+	if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) || !file_path.is_file() {
+		write_head(stream, 404, "Not Found", 0, &[])?;
+		return Ok(404);
+	}
+
+	let mut file = File::open(&file_path)?;
+	let size = file.metadata()?.len();
+	let (status, start, end) = match range.map(|range| byte_range(range, size)) {
+		None | Some(ByteRange::Invalid) => (200, 0, size),
+		Some(ByteRange::Satisfiable(start, end)) => (206, start, end + 1),
+		Some(ByteRange::Unsatisfiable) => {
+			let content_range = format!("Content-Range: bytes */{size}");
+			write_head(stream, 416, "Range Not Satisfiable", 0, &[&content_range])?;
+			return Ok(416);
+		}
+	};
+
+	if status == 206 {
+		let content_range = format!("Content-Range: bytes {start}-{}/{size}", end - 1);
+		write_head(
+			stream,
+			206,
+			"Partial Content",
+			end - start,
+			&[&content_range],
+		)?;
+	} else {
+		write_head(stream, 200, "OK", size, &[])?;
+	}
+
+	if method == "GET" {
+		file.seek(SeekFrom::Start(start))?;
+		io::copy(&mut file.take(end - start), stream)?;
+		mark_used(&file_path);
+	}
+	Ok(status)
+}
+
+fn write_head(
+	stream: &mut TcpStream,
+	status: u16,
+	reason: &str,
+	content_length: u64,
+	headers: &[&str],
+) -> Result<(), io::Error> {
+	let mut head = format!(
+		"HTTP/1.1 {status} {reason}\r\nContent-Length: {content_length}\r\nContent-Type: application/octet-stream\r\nAccept-Ranges: bytes\r\nConnection: close\r\n"
+	);
+	for header in headers {
+		head.push_str(header);
+		head.push_str("\r\n");
+	}
+	head.push_str("\r\n");
+	stream.write_all(head.as_bytes())
+}
+
+/// a Range header like bytes=0-99, bytes=100- or bytes=-100, see RFC 9110 14.2
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+	/// inclusive first and last byte within the file
+	Satisfiable(u64, u64),
+	/// valid, but starting after the end of the file
+	Unsatisfiable,
+	/// malformed, or multiple ranges, ignored by sending the whole file
+	Invalid,
+}
+
+fn byte_range(range: &str, size: u64) -> ByteRange {
+	let Some((start, end)) = range
+		.strip_prefix("bytes=")
+		.and_then(|range| range.trim().split_once('-'))
+	else {
+		return ByteRange::Invalid;
+	};
+	let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+	let (start, end) = match (digits(start), digits(end)) {
+		// suffix range, the last n bytes
+		(false, true) if start.is_empty() => match end.parse::<u64>() {
+			Ok(0) => return ByteRange::Unsatisfiable,
+			Ok(n) => (size.saturating_sub(n), u64::MAX),
+			Err(_) => return ByteRange::Invalid,
+		},
+		(true, false) if end.is_empty() => match start.parse() {
+			Ok(start) => (start, u64::MAX),
+			Err(_) => return ByteRange::Invalid,
+		},
+		(true, true) => match (start.parse(), end.parse()) {
+			(Ok(start), Ok(end)) if start <= end => (start, end),
+			_ => return ByteRange::Invalid,
+		},
+		_ => return ByteRange::Invalid,
+	};
+	if start >= size {
+		return ByteRange::Unsatisfiable;
+	}
+	ByteRange::Satisfiable(start, end.min(size - 1))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use ByteRange::*;
+
+	#[test]
+	fn satisfiable_ranges() {
+		assert_eq!(byte_range("bytes=0-99", 1000), Satisfiable(0, 99));
+		assert_eq!(byte_range("bytes=100-", 1000), Satisfiable(100, 999));
+		assert_eq!(byte_range("bytes=-100", 1000), Satisfiable(900, 999));
+		// clamped to the file
+		assert_eq!(byte_range("bytes=900-2000", 1000), Satisfiable(900, 999));
+		assert_eq!(byte_range("bytes=-2000", 1000), Satisfiable(0, 999));
+	}
+
+	#[test]
+	fn unsatisfiable_ranges_are_416() {
+		assert_eq!(byte_range("bytes=1000-", 1000), Unsatisfiable);
+		assert_eq!(byte_range("bytes=1000-1999", 1000), Unsatisfiable);
+		assert_eq!(byte_range("bytes=-0", 1000), Unsatisfiable);
+		assert_eq!(byte_range("bytes=0-", 0), Unsatisfiable);
+		assert_eq!(byte_range("bytes=-100", 0), Unsatisfiable);
+	}
+
+	#[test]
+	fn invalid_ranges_are_ignored() {
+		assert_eq!(byte_range("bytes=500-100", 1000), Invalid);
+		assert_eq!(byte_range("bytes=a-b", 1000), Invalid);
+		assert_eq!(byte_range("bytes=-", 1000), Invalid);
+		assert_eq!(byte_range("bytes=+1-2", 1000), Invalid);
+		assert_eq!(byte_range("bytes=0-1,5-6", 1000), Invalid);
+		assert_eq!(byte_range("items=0-99", 1000), Invalid);
+	}
+}
@@ -0,0 +1,298 @@
+use std::path::PathBuf;
+
+use anyhow::{Error, Result, anyhow};
+
+use crate::config::Config;
+use crate::download::{local_path, write_verified};
+use crate::esd_downloader::{FileInfo, WinEsdDownloader, esd_path};
+use crate::iso::verify_iso;
+use crate::lock::sha256_file;
+use crate::remote_cache::RemoteCache;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+	Esd,
+	Iso,
+}
+
+/// where an artifact came from, written to the lock file
+#[derive(Debug, Clone)]
+pub struct Provenance {
+	/// name of the source which resolved the artifact
+	pub source: String,
+	pub sha1size: String,
+	/// sha256:{sha256}:{size}, known once the artifact has been fetched
+	pub pin: Option<String>,
+	/// url the artifact is pinned to, if any
+	pub url: Option<String>,
+	/// url or path which actually served the artifact
+	pub served_by: Option<String>,
+	/// sha256 of the products.cab the artifact was looked up in
+	pub catalog_sha256: Option<String>,
+	/// catalog entry of the artifact
+	pub file: Option<FileInfo>,
+}
+
+/// a verified local image
+#[derive(Debug, Clone)]
+pub struct Artifact {
+	pub path: PathBuf,
+	pub kind: ArtifactKind,
+	pub provenance: Provenance,
+}
+
+impl Provenance {
+	/// pins config to this artifact
+	pub fn lock(&self, config: &mut Config) {
+		if config.sha1size.as_ref() != Some(&self.sha1size) {
+			// served_by and pin belong to the previous artifact
+			config.served_by = None;
+			config.pin = None;
+		}
+		config.sha1size = Some(self.sha1size.clone());
+		if let Some(pin) = &self.pin {
+			config.pin = Some(pin.clone());
+		}
+		if let Some(url) = &self.url {
+			config.url = Some(url.clone());
+		}
+		if let Some(served_by) = &self.served_by {
+			config.served_by = Some(served_by.clone());
+		}
+	}
+}
+
+impl Artifact {
+	/// pins config to this artifact
+	pub fn lock(&self, config: &mut Config) {
+		self.provenance.lock(config);
+	}
+}
+
+/// resolves a config to a verified local artifact
+pub trait Source {
+	fn name(&self) -> &str;
+	/// whether this source is responsible for config
+	fn handles(&self, config: &Config) -> bool;
+	fn resolve(&self, config: &Config) -> Result<Artifact, Error>;
+	/// provenance of the artifact config resolves to, without fetching it
+	fn pin(&self, config: &Config) -> Result<Provenance, Error>;
+}
+
+fn source_for<'s, 'a>(
+	sources: &'s [Box<dyn Source + 'a>],
+	config: &Config,
+) -> Result<&'s (dyn Source + 'a), Error> {
+	sources
+		.iter()
+		.find(|source| source.handles(config))
+		.map(|source| source.as_ref())
+		.ok_or_else(|| anyhow!("No source can handle this config"))
+}
+
+/// resolves config with the first source handling it
+pub fn resolve(sources: &[Box<dyn Source + '_>], config: &Config) -> Result<Artifact, Error> {
+	source_for(sources, config)?.resolve(config)
+}
+
+/// pins config with the first source handling it
+pub fn pin(sources: &[Box<dyn Source + '_>], config: &Config) -> Result<Provenance, Error> {
+	source_for(sources, config)?.pin(config)
+}
+
+/// the built-in sources, in order of precedence
+pub fn default_sources<'a>(
+	downloader: &'a WinEsdDownloader,
+	remote_cache: Option<&'a RemoteCache>,
+) -> Vec<Box<dyn Source + 'a>> {
+	vec![
+		Box::new(IsoSource),
+		Box::new(LocalSource { downloader }),
+		Box::new(HttpSource {
+			downloader,
+			remote_cache,
+		}),
+		Box::new(CatalogSource {
+			downloader,
+			remote_cache,
+		}),
+	]
+}
+
+/// returns provenance:Provenance, sha1:String, size:u64
+fn pin_esd(
+	name: &str,
+	downloader: &WinEsdDownloader,
+	config: &Config,
+) -> Result<(Provenance, String, u64), Error> {
+	let mut provenance = Provenance {
+		source: name.to_string(),
+		sha1size: String::new(),
+		pin: None,
+		url: None,
+		served_by: None,
+		catalog_sha256: None,
+		file: None,
+	};
+	let (url, sha1, size) = if config.url.is_some() {
+		downloader.pin(config)?
+	} else {
+		let (file, listed) = downloader.pin_file(config)?;
+		let cab_path = downloader.catalog_cab_path(&config.version);
+		// the history doesn't record which catalog listed its files
+		if listed && cab_path.exists() {
+			provenance.catalog_sha256 = Some(sha256_file(&cab_path)?);
+		}
+		provenance.file = Some(file.clone());
+		(file.file_path, file.sha1, file.size)
+	};
+	provenance.sha1size = format!("{sha1}:{size}");
+	provenance.url = Some(url);
+	Ok((provenance, sha1, size))
+}
+
+fn fetch_esd(
+	name: &str,
+	downloader: &WinEsdDownloader,
+	remote_cache: Option<&RemoteCache>,
+	config: &Config,
+) -> Result<Artifact, Error> {
+	let (mut provenance, sha1, size) = pin_esd(name, downloader, config)?;
+	let url = provenance.url.clone().unwrap_or_default();
+
+	// pull from the remote cache into the local one, where fetch finds it
+	let key = format!("esd/{sha1}.esd");
+	let cache_file_path = &esd_path(downloader.cache_directory(), &sha1);
+	if let Some(remote_cache) = remote_cache
+		&& !cache_file_path.exists()
+		&& let Some(digests) = remote_cache.pull(&key, cache_file_path, Some(&sha1))
+	{
+		write_verified(cache_file_path, &digests)?;
+	}
+
+	let (path, served_by, sha256) = downloader.fetch(config, &url, &sha1, &size)?;
+	if let Some(remote_cache) = remote_cache {
+		remote_cache.push(&key, &path, Some(&sha1));
+	}
+	provenance.served_by = Some(served_by);
+	provenance.pin = Some(format!("sha256:{sha256}:{size}"));
+	Ok(Artifact {
+		path,
+		kind: ArtifactKind::Esd,
+		provenance,
+	})
+}
+
+/// ESDs listed in the Microsoft catalog, used if config.url isn't set
+pub struct CatalogSource<'a> {
+	pub downloader: &'a WinEsdDownloader,
+	pub remote_cache: Option<&'a RemoteCache>,
+}
+
+impl Source for CatalogSource<'_> {
+	fn name(&self) -> &str {
+		"catalog"
+	}
+
+	fn handles(&self, config: &Config) -> bool {
+		config.iso.is_none() && config.url.is_none()
+	}
+
+	fn resolve(&self, config: &Config) -> Result<Artifact, Error> {
+		fetch_esd(self.name(), self.downloader, self.remote_cache, config)
+	}
+
+	fn pin(&self, config: &Config) -> Result<Provenance, Error> {
+		Ok(pin_esd(self.name(), self.downloader, config)?.0)
+	}
+}
+
+/// ESDs at an http(s) config.url, pinned by config.sha1size
+pub struct HttpSource<'a> {
+	pub downloader: &'a WinEsdDownloader,
+	pub remote_cache: Option<&'a RemoteCache>,
+}
+
+impl Source for HttpSource<'_> {
+	fn name(&self) -> &str {
+		"http"
+	}
+
+	fn handles(&self, config: &Config) -> bool {
+		config.iso.is_none()
+			&& config
+				.url
+				.as_ref()
+				.is_some_and(|url| local_path(url).is_none())
+	}
+
+	fn resolve(&self, config: &Config) -> Result<Artifact, Error> {
+		fetch_esd(self.name(), self.downloader, self.remote_cache, config)
+	}
+
+	fn pin(&self, config: &Config) -> Result<Provenance, Error> {
+		Ok(pin_esd(self.name(), self.downloader, config)?.0)
+	}
+}
+
+/// ESDs at a local path or file:// config.url, copied into the cache
+pub struct LocalSource<'a> {
+	pub downloader: &'a WinEsdDownloader,
+}
+
+impl Source for LocalSource<'_> {
+	fn name(&self) -> &str {
+		"local"
+	}
+
+	fn handles(&self, config: &Config) -> bool {
+		config.iso.is_none()
+			&& config
+				.url
+				.as_ref()
+				.is_some_and(|url| local_path(url).is_some())
+	}
+
+	fn resolve(&self, config: &Config) -> Result<Artifact, Error> {
+		fetch_esd(self.name(), self.downloader, None, config)
+	}
+
+	fn pin(&self, config: &Config) -> Result<Provenance, Error> {
+		Ok(pin_esd(self.name(), self.downloader, config)?.0)
+	}
+}
+
+/// windows ISO at config.iso, used in place
+pub struct IsoSource;
+
+impl Source for IsoSource {
+	fn name(&self) -> &str {
+		"iso"
+	}
+
+	fn handles(&self, config: &Config) -> bool {
+		config.iso.is_some()
+	}
+
+	fn resolve(&self, config: &Config) -> Result<Artifact, Error> {
+		let (path, sha1size, pin) = verify_iso(config)?;
+		Ok(Artifact {
+			path,
+			kind: ArtifactKind::Iso,
+			provenance: Provenance {
+				source: self.name().to_string(),
+				sha1size,
+				pin: Some(pin),
+				url: None,
+				served_by: None,
+				catalog_sha256: None,
+				file: None,
+			},
+		})
+	}
+
+	fn pin(&self, config: &Config) -> Result<Provenance, Error> {
+		// the ISO is local, hashing it is all there is to resolving it
+		Ok(self.resolve(config)?.provenance)
+	}
+}
@@ -1,5 +1,11 @@
 use anyhow::{Error, Result, anyhow};
-use std::{env, fs::{self, File}, io, path::PathBuf};
+use std::{
+	env,
+	fs::{self, File, FileTimes},
+	io,
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
 use uuid::Uuid;
 
 pub struct TmpDir {
@@ -41,3 +47,93 @@ impl<T: PartialEq + std::fmt::Debug> ExpectEqual for T {
 		Ok(self)
 	}
 }
+
+/// sets the access time, used as last use for pruning the cache.
+///
+/// Best-effort, failures like read-only caches are only reported
+pub fn mark_used(path: &PathBuf) {
+	if let Err(err) = set_accessed(path, SystemTime::now()) {
+		eprintln!("Failed to mark {path:?} as used: {err}");
+	}
+}
+
+/// sets the access time to time without opening path for writing
+pub fn set_accessed(path: &Path, time: SystemTime) -> Result<(), io::Error> {
+	let mut options = File::options();
+	options.read(true);
+	#[cfg(windows)]
+	{
+		// FILE_WRITE_ATTRIBUTES
+		std::os::windows::fs::OpenOptionsExt::access_mode(&mut options, 0x100);
+	}
+	let file = options.open(path)?;
+	file.set_times(FileTimes::new().set_accessed(time))
+}
+
+/// candidate closest to value ignoring case, for "did you mean" hints.
+///
+/// The shortest candidate starting with value wins, e.g. Professional for Pro
+pub fn closest<'a>(value: &str, candidates: &[&'a str]) -> Option<&'a str> {
+	let value = value.to_lowercase();
+	let prefixed = candidates
+		.iter()
+		.filter(|candidate| candidate.to_lowercase().starts_with(&value))
+		.min_by_key(|candidate| candidate.len());
+	if let Some(prefixed) = prefixed {
+		return Some(prefixed);
+	}
+	candidates
+		.iter()
+		.map(|candidate| (levenshtein(&value, &candidate.to_lowercase()), *candidate))
+		.filter(|(distance, candidate)| *distance <= value.len().max(candidate.len()) / 2)
+		.min_by_key(|(distance, _)| *distance)
+		.map(|(_, candidate)| candidate)
+}
+
+/// edit distance between a and b
+fn levenshtein(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut diagonal = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = diagonal + usize::from(ca != *cb);
+			diagonal = row[j + 1];
+			row[j + 1] = substitution.min(diagonal + 1).min(row[j] + 1);
+		}
+	}
+	row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn levenshtein_distance() {
+		assert_eq!(levenshtein("", ""), 0);
+		assert_eq!(levenshtein("", "abc"), 3);
+		assert_eq!(levenshtein("kitten", "sitting"), 3);
+		assert_eq!(levenshtein("en-us", "en-us"), 0);
+		assert_eq!(levenshtein("en-su", "en-us"), 2);
+	}
+
+	#[test]
+	fn closest_prefers_shortest_prefix() {
+		let editions = ["Professional", "ProfessionalN", "Pro", "Pro N", "Home"];
+		assert_eq!(closest("pro", &editions), Some("Pro"));
+		assert_eq!(closest("Profession", &editions), Some("Professional"));
+		assert_eq!(closest("Hom", &editions), Some("Home"));
+	}
+
+	#[test]
+	fn closest_by_edit_distance() {
+		let langs = ["en-us", "en-gb", "de-de"];
+		assert_eq!(closest("en-su", &langs), Some("en-us"));
+		assert_eq!(closest("DE-DR", &langs), Some("de-de"));
+		// too far from anything
+		assert_eq!(closest("zh-cn", &langs), None);
+		assert_eq!(closest("en-us", &[]), None);
+	}
+}